@@ -0,0 +1,30 @@
+use super::admin_types::{
+    GetLogLevelResponse, ListLogsResponse, ListLogsResponseBody, LogLevelBody, SetLogLevelRequest,
+    SetLogLevelResponse,
+};
+use crate::api::json_decode;
+use ic_http::{log_info, log_level, recent_logs, set_log_level};
+use ic_http_certification::{HttpRequest, HttpResponse};
+use matchit::Params;
+
+pub fn get_log_level_handler(_req: &HttpRequest, _params: &Params) -> HttpResponse<'static> {
+    GetLogLevelResponse::ok(LogLevelBody { level: log_level() })
+}
+
+pub fn set_log_level_handler(req: &HttpRequest, _params: &Params) -> HttpResponse<'static> {
+    let req_body: SetLogLevelRequest = match json_decode(req.body()) {
+        Ok(req_body) => req_body,
+        Err(response) => return response,
+    };
+
+    set_log_level(req_body.level);
+    log_info!(target: "set_log_level_handler", "Log level set to {}", req_body.level);
+
+    SetLogLevelResponse::ok(())
+}
+
+pub fn list_logs_handler(_req: &HttpRequest, _params: &Params) -> HttpResponse<'static> {
+    ListLogsResponse::ok(ListLogsResponseBody {
+        logs: recent_logs(),
+    })
+}
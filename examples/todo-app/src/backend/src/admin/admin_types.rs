@@ -0,0 +1,21 @@
+use crate::api::ApiResponse;
+use ic_http::{LogEntry, LogLevel};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLevelBody {
+    pub level: LogLevel,
+}
+
+pub type GetLogLevelResponse = ApiResponse<LogLevelBody>;
+
+pub type SetLogLevelRequest = LogLevelBody;
+
+pub type SetLogLevelResponse = ApiResponse;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListLogsResponseBody {
+    pub logs: Vec<LogEntry>,
+}
+
+pub type ListLogsResponse = ApiResponse<ListLogsResponseBody>;
@@ -0,0 +1,4 @@
+mod admin_routes;
+mod admin_types;
+
+pub use admin_routes::*;
@@ -3,6 +3,11 @@ use ic_http_certification::{HttpResponse, StatusCode};
 use serde::Serialize;
 use std::borrow::Cow;
 
+/// Challenge sent along with `401 Unauthorized` responses, pointing clients
+/// at the HTTP Message Signatures headers that authenticate a request.
+pub const SIGNATURE_AUTH_CHALLENGE: &str =
+    r#"Signature realm="ic", headers="signature signature-input signature-key""#;
+
 #[derive(Debug, Clone, Serialize)]
 pub enum ApiResponseBody<T = ()> {
     #[serde(rename = "ok")]
@@ -34,12 +39,40 @@ impl<'a, T: Serialize> ApiResponse<T> {
         Self::failure(StatusCode::BAD_REQUEST, message).build()
     }
 
-    #[allow(dead_code)]
+    pub fn unauthorized() -> HttpResponse<'a> {
+        let mut response =
+            Self::failure(StatusCode::UNAUTHORIZED, "Unauthorized".to_string()).build();
+        response.add_header((
+            "www-authenticate".to_string(),
+            SIGNATURE_AUTH_CHALLENGE.to_string(),
+        ));
+
+        response
+    }
+
+    pub fn forbidden() -> HttpResponse<'a> {
+        Self::failure(StatusCode::FORBIDDEN, "Forbidden".to_string()).build()
+    }
+
+    pub fn unsupported_media_type(supported: &[&str]) -> HttpResponse<'a> {
+        Self::failure(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!(
+                "Unsupported media type, expected one of: {}",
+                supported.join(", ")
+            ),
+        )
+        .build()
+    }
+
+    pub fn unprocessable_entity(message: String) -> HttpResponse<'a> {
+        Self::failure(StatusCode::UNPROCESSABLE_ENTITY, message).build()
+    }
+
     pub fn not_found() -> HttpResponse<'a> {
         Self::failure(StatusCode::NOT_FOUND, "Not found".to_string()).build()
     }
 
-    #[allow(dead_code)]
     pub fn not_allowed() -> HttpResponse<'a> {
         Self::failure(
             StatusCode::METHOD_NOT_ALLOWED,
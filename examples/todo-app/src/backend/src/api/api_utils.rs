@@ -1,10 +1,16 @@
+use super::ErrorResponse;
+use crate::metrics;
+use ic_http_certification::{HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
 
-pub fn json_decode<T>(value: &[u8]) -> T
+pub fn json_decode<T>(value: &[u8]) -> Result<T, HttpResponse<'static>>
 where
     T: for<'de> Deserialize<'de>,
 {
-    serde_json::from_slice(value).expect("Failed to deserialize value")
+    serde_json::from_slice(value).map_err(|err| {
+        metrics::record_decode_failure();
+        ErrorResponse::bad_request(format!("Invalid request body: {err}"))
+    })
 }
 
 pub fn json_encode<T>(value: &T) -> Vec<u8>
@@ -13,3 +19,12 @@ where
 {
     serde_json::to_vec(value).expect("Failed to serialize value")
 }
+
+/// Returns the media type of the request body, without parameters such as `charset`.
+pub fn content_type(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .and_then(|(_, value)| value.split(';').next())
+        .map(|media_type| media_type.trim().to_ascii_lowercase())
+}
@@ -1,16 +1,15 @@
+use crate::metrics::METRICS_PATH;
 use ic_asset_certification::{
     Asset, AssetConfig, AssetEncoding, AssetFallbackConfig, AssetMap, AssetRedirectKind,
     AssetRouter,
 };
-use ic_cdk::api::{canister_cycle_balance, certified_data_set, data_certificate};
+use ic_cdk::api::{certified_data_set, data_certificate};
 use ic_http_certification::{
     utils::add_v2_certificate_header, DefaultCelBuilder, HeaderField, HttpCertification,
     HttpCertificationPath, HttpCertificationTree, HttpCertificationTreeEntry, HttpRequest,
     HttpResponse, StatusCode, CERTIFICATE_EXPRESSION_HEADER_NAME,
 };
 use include_dir::{include_dir, Dir};
-use matchit::Params;
-use serde::Serialize;
 use std::{cell::RefCell, rc::Rc};
 
 thread_local! {
@@ -22,11 +21,10 @@ static ASSETS_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/../frontend/dist"
 const IMMUTABLE_ASSET_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
 const NO_CACHE_ASSET_CACHE_CONTROL: &str = "public, no-cache, no-store";
 
-#[derive(Debug, Clone, Serialize)]
-pub struct Metrics {
+#[derive(Debug, Clone, Copy)]
+pub struct AssetCounts {
     pub num_assets: usize,
     pub num_fallback_assets: usize,
-    pub cycle_balance: u128,
 }
 
 pub fn certify_all_assets() {
@@ -97,7 +95,7 @@ pub fn certify_all_assets() {
     HTTP_TREE.with(|tree| {
         let mut tree = tree.borrow_mut();
 
-        let metrics_tree_path = HttpCertificationPath::exact("/metrics");
+        let metrics_tree_path = HttpCertificationPath::exact(METRICS_PATH);
         let metrics_certification = HttpCertification::skip();
         let metrics_tree_entry =
             HttpCertificationTreeEntry::new(metrics_tree_path, metrics_certification);
@@ -127,47 +125,48 @@ pub fn serve_asset(req: &HttpRequest) -> HttpResponse<'static> {
     })
 }
 
-pub fn serve_metrics(_req: &HttpRequest, _params: &Params) -> HttpResponse<'static> {
-    ASSET_ROUTER.with_borrow(|asset_router| {
-        let metrics = Metrics {
-            num_assets: asset_router.get_assets().len(),
-            num_fallback_assets: asset_router.get_fallback_assets().len(),
-            cycle_balance: canister_cycle_balance(),
-        };
-        let body = serde_json::to_vec(&metrics).expect("Failed to serialize metrics");
-        let headers = get_asset_headers(vec![
-            (
-                CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(),
-                DefaultCelBuilder::skip_certification().to_string(),
-            ),
-            ("content-type".to_string(), "application/json".to_string()),
-            (
-                "cache-control".to_string(),
-                NO_CACHE_ASSET_CACHE_CONTROL.to_string(),
-            ),
-        ]);
-        let mut response = HttpResponse::builder()
-            .with_status_code(StatusCode::OK)
-            .with_body(body)
-            .with_headers(headers)
-            .build();
-
-        HTTP_TREE.with(|tree| {
-            let tree = tree.borrow();
-
-            let metrics_tree_path = HttpCertificationPath::exact("/metrics");
-            let metrics_certification = HttpCertification::skip();
-            let metrics_tree_entry =
-                HttpCertificationTreeEntry::new(&metrics_tree_path, metrics_certification);
-            add_v2_certificate_header(
-                &data_certificate().expect("No data certificate available"),
-                &mut response,
-                &tree.witness(&metrics_tree_entry, "/metrics").unwrap(),
-                &metrics_tree_path.to_expr_path(),
-            );
+pub fn get_asset_counts() -> AssetCounts {
+    ASSET_ROUTER.with_borrow(|asset_router| AssetCounts {
+        num_assets: asset_router.get_assets().len(),
+        num_fallback_assets: asset_router.get_fallback_assets().len(),
+    })
+}
 
-            response
-        })
+/// Builds an uncertified response for the metrics endpoint, attaching the
+/// witness of the skip certification entry inserted by [certify_all_assets].
+pub fn create_metrics_response(body: Vec<u8>, content_type: &str) -> HttpResponse<'static> {
+    let headers = get_asset_headers(vec![
+        (
+            CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(),
+            DefaultCelBuilder::skip_certification().to_string(),
+        ),
+        ("content-type".to_string(), content_type.to_string()),
+        (
+            "cache-control".to_string(),
+            NO_CACHE_ASSET_CACHE_CONTROL.to_string(),
+        ),
+    ]);
+    let mut response = HttpResponse::builder()
+        .with_status_code(StatusCode::OK)
+        .with_body(body)
+        .with_headers(headers)
+        .build();
+
+    HTTP_TREE.with(|tree| {
+        let tree = tree.borrow();
+
+        let metrics_tree_path = HttpCertificationPath::exact(METRICS_PATH);
+        let metrics_certification = HttpCertification::skip();
+        let metrics_tree_entry =
+            HttpCertificationTreeEntry::new(&metrics_tree_path, metrics_certification);
+        add_v2_certificate_header(
+            &data_certificate().expect("No data certificate available"),
+            &mut response,
+            &tree.witness(&metrics_tree_entry, METRICS_PATH).unwrap(),
+            &metrics_tree_path.to_expr_path(),
+        );
+
+        response
     })
 }
 
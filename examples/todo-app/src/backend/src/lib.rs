@@ -1,15 +1,18 @@
+mod admin;
 mod api;
 mod assets;
+mod metrics;
 mod router;
 mod todo;
 
+use admin::*;
 use api::ErrorResponse;
 use assets::*;
 use ic_cdk::*;
 use ic_http_certification::{HttpRequest, HttpResponse};
-use matchit::Router;
+use metrics::{METRICS_PATH, serve_metrics};
 use once_cell::sync::OnceCell;
-use router::MethodRouter;
+use router::{ApiRouter, AuthPolicy, MethodRouter};
 use todo::*;
 
 #[init]
@@ -22,96 +25,76 @@ fn post_upgrade() {
     certify_all_assets();
 }
 
-#[query(
-    decode_with = "ic_http::decode_args",
-    encode_with = "ic_http::encode_result"
-)]
-fn http_request_v2(req: HttpRequest) -> HttpResponse<'static> {
+ic_http::http_entry_points!(query = http_query, update = http_update);
+
+fn http_query(req: HttpRequest) -> HttpResponse<'static> {
     let path = req.get_path().expect("Failed to parse request path");
 
+    if path == METRICS_PATH {
+        return serve_metrics(&req);
+    }
+
     if path.starts_with("/api") {
-        // [TODO] - return metrics on query
         return HttpResponse::builder().with_upgrade(true).build();
     }
 
     serve_asset(&req)
 }
 
-#[update(
-    decode_with = "ic_http::decode_args",
-    encode_with = "ic_http::encode_result"
-)]
-fn http_request_update_v2(req: HttpRequest) -> HttpResponse<'static> {
+fn http_update(req: HttpRequest) -> HttpResponse<'static> {
     let path = req.get_path().expect("Failed to parse request path");
 
     if path.starts_with("/api") {
-        return serve_api_route(&req);
+        return get_api_router().handle(&req);
     }
 
     ErrorResponse::bad_request("Update calls not allowed for certified static assets".to_string())
 }
 
-fn serve_api_route(req: &HttpRequest) -> HttpResponse<'static> {
-    let router = get_api_router();
-    let path = req.get_path().expect("Failed to parse request path");
-
-    let route_match = router.at(&path);
-    ic_cdk::println!(
-        "[serve_api_route] Route match result: {:?}",
-        route_match.is_ok()
-    );
-
-    let Ok(handler) = route_match else {
-        ic_cdk::println!("[serve_api_route] No route found for path: {}", path);
-
-        // Log the specific matchit error
-        match route_match.err().unwrap() {
-            matchit::MatchError::NotFound => {
-                ic_cdk::println!("[serve_api_route] Error: Path not found in router");
-            }
-        }
-
-        return HttpResponse::not_found(b"Not Found", vec![]).build();
-    };
-
-    handler.value.route(req, &handler.params)
-}
-
-fn get_api_router() -> &'static Router<MethodRouter> {
-    static API_ROUTER: OnceCell<Router<MethodRouter>> = OnceCell::new();
+fn get_api_router() -> &'static ApiRouter {
+    static API_ROUTER: OnceCell<ApiRouter> = OnceCell::new();
 
     API_ROUTER.get_or_init(|| {
-        let mut router = Router::new();
-
-        router
-            .insert(
+        ApiRouter::new()
+            .route(
                 "/api/todos",
                 MethodRouter::new()
+                    .auth(AuthPolicy::Authenticated)
                     .get(list_todo_items_handler)
                     .post(create_todo_item_handler)
                     .build(),
             )
-            .unwrap();
-
-        router
-            .insert(
+            .route(
+                "/api/todos:batch",
+                MethodRouter::new()
+                    .auth(AuthPolicy::Authenticated)
+                    .post(batch_todo_items_handler)
+                    .build(),
+            )
+            .route(
                 "/api/todos/{id}",
                 MethodRouter::new()
+                    .auth(AuthPolicy::Authenticated)
                     .get(get_todo_item_handler)
-                    .patch(update_todo_item_handler)
-                    .put(update_todo_item_handler)
+                    .patch(patch_todo_item_handler)
+                    .put(replace_todo_item_handler)
                     .delete(delete_todo_item_handler)
                     .build(),
             )
-            .unwrap();
-
-        router
-            .insert(
-                "/api/metrics",
-                MethodRouter::new().get(serve_metrics).build(),
+            .route(
+                "/api/admin/log-level",
+                MethodRouter::new()
+                    .auth(AuthPolicy::Controllers)
+                    .get(get_log_level_handler)
+                    .put(set_log_level_handler)
+                    .build(),
+            )
+            .route(
+                "/api/admin/logs",
+                MethodRouter::new()
+                    .auth(AuthPolicy::Controllers)
+                    .get(list_logs_handler)
+                    .build(),
             )
-            .unwrap();
-
-        router
     })
 }
@@ -0,0 +1,274 @@
+use crate::{
+    assets::{create_metrics_response, get_asset_counts},
+    todo::get_todo_counts,
+};
+use ic_cdk::{api::canister_cycle_balance, stable::stable_size};
+use ic_http_certification::{HttpRequest, HttpResponse, Method, StatusCode};
+use serde::Serialize;
+use std::{cell::RefCell, collections::BTreeMap, fmt::Write};
+
+pub const METRICS_PATH: &str = "/metrics";
+
+const WASM_PAGE_SIZE_BYTES: u64 = 65_536;
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Prefix of every metric name, so they don't collide with the metrics of other services.
+const METRIC_NAMESPACE: &str = "todo_app";
+
+thread_local! {
+    static COUNTERS: RefCell<Counters> = RefCell::default();
+}
+
+/// Counters are only incremented during update calls, since state changes made
+/// by query calls are discarded. They are kept in heap memory only, so they are
+/// reset to zero when the canister is upgraded.
+#[derive(Debug, Default)]
+struct Counters {
+    requests: BTreeMap<RequestKey, u64>,
+    decode_failures: u64,
+    auth_failures: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RequestKey {
+    route: String,
+    method: String,
+    status: u16,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Metrics {
+    pub num_assets: usize,
+    pub num_fallback_assets: usize,
+    pub cycle_balance: u128,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub todo_users: usize,
+    pub todo_items: usize,
+    pub completed_todo_items: usize,
+    pub decode_failures: u64,
+    pub auth_failures: u64,
+    pub requests: Vec<RequestCount>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestCount {
+    pub route: String,
+    pub method: String,
+    pub status: u16,
+    pub count: u64,
+}
+
+pub fn record_request(route: &str, method: &Method, status: StatusCode) {
+    let key = RequestKey {
+        route: route.to_string(),
+        method: method.to_string(),
+        status: status.as_u16(),
+    };
+
+    COUNTERS.with_borrow_mut(|counters| *counters.requests.entry(key).or_default() += 1);
+}
+
+pub fn record_decode_failure() {
+    COUNTERS.with_borrow_mut(|counters| counters.decode_failures += 1);
+}
+
+pub fn record_auth_failure() {
+    COUNTERS.with_borrow_mut(|counters| counters.auth_failures += 1);
+}
+
+/// Serves the metrics in the Prometheus text exposition format, or as JSON if
+/// the client prefers it through the `accept` header.
+pub fn serve_metrics(req: &HttpRequest) -> HttpResponse<'static> {
+    let metrics = collect_metrics();
+
+    if accepts_json(req) {
+        let body = serde_json::to_vec(&metrics).expect("Failed to serialize metrics");
+        return create_metrics_response(body, JSON_CONTENT_TYPE);
+    }
+
+    create_metrics_response(
+        encode_prometheus(&metrics).into_bytes(),
+        PROMETHEUS_CONTENT_TYPE,
+    )
+}
+
+fn collect_metrics() -> Metrics {
+    let asset_counts = get_asset_counts();
+    let todo_counts = get_todo_counts();
+
+    COUNTERS.with_borrow(|counters| Metrics {
+        num_assets: asset_counts.num_assets,
+        num_fallback_assets: asset_counts.num_fallback_assets,
+        cycle_balance: canister_cycle_balance(),
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: stable_size() * WASM_PAGE_SIZE_BYTES,
+        todo_users: todo_counts.users,
+        todo_items: todo_counts.items,
+        completed_todo_items: todo_counts.completed_items,
+        decode_failures: counters.decode_failures,
+        auth_failures: counters.auth_failures,
+        requests: counters
+            .requests
+            .iter()
+            .map(|(key, count)| RequestCount {
+                route: key.route.clone(),
+                method: key.method.clone(),
+                status: key.status,
+                count: *count,
+            })
+            .collect(),
+    })
+}
+
+/// Whether the `accept` header prefers JSON over the Prometheus text format. When both
+/// have the same quality, the text format is served.
+fn accepts_json(req: &HttpRequest) -> bool {
+    let Some(accept) = req
+        .headers()
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("accept"))
+        .map(|(_, value)| value.as_str())
+    else {
+        return false;
+    };
+
+    let json_quality = media_range_quality(accept, &[JSON_CONTENT_TYPE, "application/*"]);
+    let text_quality = media_range_quality(accept, &["text/plain", "text/*", "*/*"]);
+
+    json_quality > 0.0 && json_quality > text_quality
+}
+
+/// The highest quality given by the `accept` header to one of the media ranges, or 0 if
+/// none of them is accepted.
+fn media_range_quality(accept: &str, media_ranges: &[&str]) -> f32 {
+    accept
+        .split(',')
+        .filter_map(|media_range| {
+            let mut params = media_range.split(';');
+            let media_type = params.next()?.trim();
+            if !media_ranges
+                .iter()
+                .any(|range| range.eq_ignore_ascii_case(media_type))
+            {
+                return None;
+            }
+
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.trim().parse::<f32>().ok())?;
+            Some(quality)
+        })
+        .fold(0.0, f32::max)
+}
+
+fn encode_prometheus(metrics: &Metrics) -> String {
+    let mut out = String::new();
+
+    write_gauge(
+        &mut out,
+        "assets",
+        "Number of certified assets.",
+        metrics.num_assets,
+    );
+    write_gauge(
+        &mut out,
+        "fallback_assets",
+        "Number of certified fallback assets.",
+        metrics.num_fallback_assets,
+    );
+    write_gauge(
+        &mut out,
+        "cycle_balance",
+        "Cycle balance of the canister.",
+        metrics.cycle_balance,
+    );
+    write_gauge(
+        &mut out,
+        "heap_memory_bytes",
+        "Size of the canister heap memory in bytes.",
+        metrics.heap_memory_bytes,
+    );
+    write_gauge(
+        &mut out,
+        "stable_memory_bytes",
+        "Size of the canister stable memory in bytes.",
+        metrics.stable_memory_bytes,
+    );
+    write_gauge(
+        &mut out,
+        "todo_users",
+        "Number of users that have a todo list.",
+        metrics.todo_users,
+    );
+
+    write_header(&mut out, "todo_items", "Number of todo items.", "gauge");
+    let open_items = metrics.todo_items - metrics.completed_todo_items;
+    writeln!(
+        out,
+        "{METRIC_NAMESPACE}_todo_items{{completed=\"false\"}} {open_items}"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "{METRIC_NAMESPACE}_todo_items{{completed=\"true\"}} {}",
+        metrics.completed_todo_items
+    )
+    .unwrap();
+
+    write_counter(
+        &mut out,
+        "decode_failures_total",
+        "Number of request bodies that failed to decode.",
+        metrics.decode_failures,
+    );
+    write_counter(
+        &mut out,
+        "auth_failures_total",
+        "Number of requests rejected by the route auth policy.",
+        metrics.auth_failures,
+    );
+
+    write_header(
+        &mut out,
+        "http_requests_total",
+        "Number of API requests, by route, method and status.",
+        "counter",
+    );
+    for request in &metrics.requests {
+        writeln!(
+            out,
+            "{METRIC_NAMESPACE}_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+            request.route, request.method, request.status, request.count
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    write_header(out, name, help, "gauge");
+    writeln!(out, "{METRIC_NAMESPACE}_{name} {value}").unwrap();
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    write_header(out, name, help, "counter");
+    writeln!(out, "{METRIC_NAMESPACE}_{name} {value}").unwrap();
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, metric_type: &str) {
+    writeln!(out, "# HELP {METRIC_NAMESPACE}_{name} {help}").unwrap();
+    writeln!(out, "# TYPE {METRIC_NAMESPACE}_{name} {metric_type}").unwrap();
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_memory_bytes() -> u64 {
+    core::arch::wasm32::memory_size::<0>() as u64 * WASM_PAGE_SIZE_BYTES
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_memory_bytes() -> u64 {
+    0
+}
@@ -1,22 +1,76 @@
+use crate::{api::ErrorResponse, metrics};
+use candid::Principal;
+use ic_cdk::api::{is_controller, msg_caller};
+use ic_http::log_warn;
 use ic_http_certification::{HttpRequest, HttpResponse, Method};
-use matchit::Params;
+use matchit::{Params, Router};
 use std::collections::HashMap;
 
 pub type RouteHandler = for<'a> fn(&'a HttpRequest, &'a Params) -> HttpResponse<'static>;
 
 type MethodMap = HashMap<Method, RouteHandler>;
 
+/// Route label used in metrics for requests that did not match any route.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Declares which callers are allowed to reach the handlers of a route.
+#[derive(Debug, Clone, Default)]
+pub enum AuthPolicy {
+    /// Only callers that signed the request, i.e. not the anonymous principal.
+    #[default]
+    Authenticated,
+    /// Any caller, including the anonymous principal.
+    #[allow(dead_code)]
+    AllowAnonymous,
+    /// Only the listed principals.
+    #[allow(dead_code)]
+    AllowList(Vec<Principal>),
+    /// Only the controllers of the canister.
+    Controllers,
+}
+
+impl AuthPolicy {
+    fn authorize(&self, caller: &Principal) -> Result<(), HttpResponse<'static>> {
+        self.authorize_with(caller, is_controller)
+    }
+
+    /// Checks the caller, asking `is_controller` whether it controls the canister.
+    fn authorize_with(
+        &self,
+        caller: &Principal,
+        is_controller: impl FnOnce(&Principal) -> bool,
+    ) -> Result<(), HttpResponse<'static>> {
+        match self {
+            AuthPolicy::AllowAnonymous => Ok(()),
+            _ if *caller == Principal::anonymous() => Err(ErrorResponse::unauthorized()),
+            AuthPolicy::Authenticated => Ok(()),
+            AuthPolicy::AllowList(principals) if principals.contains(caller) => Ok(()),
+            AuthPolicy::AllowList(_) => Err(ErrorResponse::forbidden()),
+            AuthPolicy::Controllers if is_controller(caller) => Ok(()),
+            AuthPolicy::Controllers => Err(ErrorResponse::forbidden()),
+        }
+    }
+}
+
 pub struct MethodRouter {
     routes: MethodMap,
+    auth_policy: AuthPolicy,
 }
 
 impl MethodRouter {
     pub fn new() -> Self {
         Self {
             routes: HashMap::new(),
+            auth_policy: AuthPolicy::default(),
         }
     }
 
+    pub fn auth(mut self, auth_policy: AuthPolicy) -> Self {
+        self.auth_policy = auth_policy;
+
+        self
+    }
+
     pub fn get(self, handler: RouteHandler) -> Self {
         self.add_route(Method::GET, handler)
     }
@@ -41,8 +95,15 @@ impl MethodRouter {
         self
     }
 
-    pub fn route(&self, req: &HttpRequest, params: &Params) -> HttpResponse<'_> {
-        let handler = self.routes.get(req.method()).unwrap();
+    pub fn route(&self, req: &HttpRequest, params: &Params) -> HttpResponse<'static> {
+        if let Err(response) = self.auth_policy.authorize(&msg_caller()) {
+            metrics::record_auth_failure();
+            return response;
+        }
+
+        let Some(handler) = self.routes.get(req.method()) else {
+            return ErrorResponse::not_allowed();
+        };
 
         handler(req, params)
     }
@@ -53,3 +114,149 @@ impl MethodRouter {
         self
     }
 }
+
+struct Route {
+    path: String,
+    method_router: MethodRouter,
+}
+
+/// Matches API requests to their [MethodRouter] and records request metrics
+/// for every response, so that handlers don't need to.
+pub struct ApiRouter {
+    router: Router<Route>,
+}
+
+impl ApiRouter {
+    pub fn new() -> Self {
+        Self {
+            router: Router::new(),
+        }
+    }
+
+    pub fn route(mut self, path: &str, method_router: MethodRouter) -> Self {
+        let route = Route {
+            path: path.to_string(),
+            method_router,
+        };
+        self.router
+            .insert(path, route)
+            .unwrap_or_else(|err| panic!("Failed to insert route {path}: {err}"));
+
+        self
+    }
+
+    pub fn handle(&self, req: &HttpRequest) -> HttpResponse<'static> {
+        ic_http::begin_request(req);
+
+        let (route_path, response) = self.match_and_route(req);
+        metrics::record_request(route_path, req.method(), response.status_code());
+
+        response
+    }
+
+    /// Routes a request without starting a new logging context or recording metrics,
+    /// for requests that are nested in another one, such as batch operations.
+    pub fn dispatch(&self, req: &HttpRequest) -> HttpResponse<'static> {
+        let (_, response) = self.match_and_route(req);
+
+        response
+    }
+
+    fn match_and_route(&self, req: &HttpRequest) -> (&str, HttpResponse<'static>) {
+        let path = req.get_path().expect("Failed to parse request path");
+
+        match self.router.at(&path) {
+            Ok(route_match) => {
+                let route = route_match.value;
+                let response = route.method_router.route(req, &route_match.params);
+
+                (route.path.as_str(), response)
+            }
+            Err(_) => {
+                log_warn!(target: "ApiRouter", "No route found for path: {}", path);
+                (UNMATCHED_ROUTE, ErrorResponse::not_found())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_http_certification::StatusCode;
+
+    use super::*;
+
+    fn user() -> Principal {
+        Principal::self_authenticating([1; 32])
+    }
+
+    fn other_user() -> Principal {
+        Principal::self_authenticating([2; 32])
+    }
+
+    fn status(result: Result<(), HttpResponse<'static>>) -> Option<StatusCode> {
+        result.err().map(|response| response.status_code())
+    }
+
+    #[test]
+    fn anonymous_callers_get_a_signature_challenge() {
+        let response = AuthPolicy::Authenticated
+            .authorize_with(&Principal::anonymous(), |_| true)
+            .unwrap_err();
+
+        assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().iter().any(|(name, value)| {
+            name == "www-authenticate" && value == crate::api::SIGNATURE_AUTH_CHALLENGE
+        }));
+    }
+
+    #[test]
+    fn authenticated_allows_any_signed_caller() {
+        assert_eq!(
+            status(AuthPolicy::Authenticated.authorize_with(&user(), |_| false)),
+            None
+        );
+    }
+
+    #[test]
+    fn allow_anonymous_allows_every_caller() {
+        let policy = AuthPolicy::AllowAnonymous;
+
+        assert_eq!(
+            status(policy.authorize_with(&Principal::anonymous(), |_| false)),
+            None
+        );
+        assert_eq!(status(policy.authorize_with(&user(), |_| false)), None);
+    }
+
+    #[test]
+    fn allow_list_only_allows_the_listed_callers() {
+        let policy = AuthPolicy::AllowList(vec![user()]);
+
+        assert_eq!(status(policy.authorize_with(&user(), |_| false)), None);
+        assert_eq!(
+            status(policy.authorize_with(&other_user(), |_| true)),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(policy.authorize_with(&Principal::anonymous(), |_| false)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn controllers_only_allows_the_controllers() {
+        let policy = AuthPolicy::Controllers;
+        let is_controller = |caller: &Principal| *caller == user();
+
+        assert_eq!(status(policy.authorize_with(&user(), is_controller)), None);
+        assert_eq!(
+            status(policy.authorize_with(&other_user(), is_controller)),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(policy.authorize_with(&Principal::anonymous(), |_| true)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+}
@@ -2,3 +2,4 @@ mod todo_routes;
 mod todo_types;
 
 pub use todo_routes::*;
+pub use todo_types::TodoCounts;
@@ -1,15 +1,28 @@
 use super::todo_types::{
-    CreateTodoItemRequest, CreateTodoItemResponse, DeleteTodoItemResponse, GetTodoItemResponse,
-    ListTodosResponse, ListTodosResponseBody, TodoItem, UpdateTodoItemRequest,
-    UpdateTodoItemResponse,
+    BatchOperation, BatchOperationResult, BatchTodoItemsRequest, BatchTodoItemsResponse,
+    BatchTodoItemsResponseBody, CreateTodoItemRequest, CreateTodoItemResponse,
+    DeleteTodoItemResponse, GetTodoItemResponse, ListTodosResponse, ListTodosResponseBody,
+    ReplaceTodoItemRequest, TodoCounts, TodoItem, UpdateTodoItemResponse,
+};
+use crate::{
+    api::{ErrorResponse, content_type, json_decode, json_encode},
+    get_api_router,
 };
-use crate::api::json_decode;
 use ic_cdk::api::msg_caller;
-use ic_http_certification::{HttpRequest, HttpResponse};
+use ic_http::{log_debug, log_warn};
+use ic_http_certification::{HttpRequest, HttpResponse, Method, StatusCode};
 use matchit::Params;
 use once_cell::sync::OnceCell;
+use serde_json::Value;
 use std::{cell::RefCell, collections::HashMap, sync::Mutex};
 
+const MAX_BATCH_OPERATIONS: usize = 100;
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+const PATCH_CONTENT_TYPES: &[&str] = &[MERGE_PATCH_CONTENT_TYPE, JSON_PATCH_CONTENT_TYPE];
+
 thread_local! {
     static NEXT_TODO_ID: RefCell<u32> = const { RefCell::new(0) };
     static TODO_ITEMS: RefCell<UserTodoMap> = RefCell::<UserTodoMap>::new(UserTodoMap::new());
@@ -25,18 +38,89 @@ fn todos() -> &'static Mutex<UserTodoMap> {
     INSTANCE.get_or_init(|| Mutex::new(UserTodoMap::new()))
 }
 
+/// A copy of the todos that a batch touches, used to roll back batches that fail part way through.
+struct TodoSnapshot {
+    next_todo_id: u32,
+    user_id: String,
+    had_user_todos: bool,
+    /// The previous value of each updated or deleted todo item.
+    todo_items: Vec<(u32, Option<TodoItem>)>,
+}
+
+impl TodoSnapshot {
+    fn take(user_id: String, operations: &[BatchOperation]) -> Self {
+        let all_todos = todos().lock().unwrap();
+        let user_todos = all_todos.get(&user_id);
+        let todo_items = operations
+            .iter()
+            .filter_map(|operation| match operation {
+                BatchOperation::Create(_) => None,
+                BatchOperation::Update { id, .. } | BatchOperation::Delete { id } => Some(*id),
+            })
+            .map(|id| (id, user_todos.and_then(|todos| todos.get(&id)).cloned()))
+            .collect();
+
+        Self {
+            next_todo_id: NEXT_TODO_ID.with_borrow(|id| *id),
+            had_user_todos: user_todos.is_some(),
+            user_id,
+            todo_items,
+        }
+    }
+
+    fn restore(self) {
+        let created_ids = self.next_todo_id..NEXT_TODO_ID.with_borrow(|id| *id);
+        NEXT_TODO_ID.set(self.next_todo_id);
+
+        let mut all_todos = todos().lock().unwrap();
+        let user_todos = all_todos.entry(self.user_id.clone()).or_default();
+        for id in created_ids {
+            user_todos.remove(&id);
+        }
+        for (id, todo_item) in self.todo_items {
+            match todo_item {
+                Some(todo_item) => user_todos.insert(id, todo_item),
+                None => user_todos.remove(&id),
+            };
+        }
+        if !self.had_user_todos && user_todos.is_empty() {
+            all_todos.remove(&self.user_id);
+        }
+    }
+}
+
+pub fn get_todo_counts() -> TodoCounts {
+    let all_todos = todos().lock().unwrap();
+
+    all_todos
+        .values()
+        .flat_map(|user_todos| user_todos.values())
+        .fold(
+            TodoCounts {
+                users: all_todos.len(),
+                ..Default::default()
+            },
+            |mut counts, todo| {
+                counts.items += 1;
+                if todo.completed {
+                    counts.completed_items += 1;
+                }
+                counts
+            },
+        )
+}
+
 pub fn get_todo_item_handler(req: &HttpRequest, params: &Params) -> HttpResponse<'static> {
-    ic_cdk::println!("[get_todo_item_handler] Processing request: {:?}", req);
     let user_principal = msg_caller();
 
-    ic_cdk::println!("[get_todo_item_handler] User principal: {}", user_principal);
+    log_debug!(target: "get_todo_item_handler", "User principal: {}", user_principal);
 
     let Some(id_str) = params.get("id") else {
-        ic_cdk::println!("[get_todo_item_handler] Missing ID parameter");
+        log_warn!(target: "get_todo_item_handler", "Missing ID parameter");
         return HttpResponse::bad_request(b"Missing ID parameter", vec![]).build();
     };
     let Ok(id) = id_str.parse::<u32>() else {
-        ic_cdk::println!("[get_todo_item_handler] Invalid ID format: {}", id_str);
+        log_warn!(target: "get_todo_item_handler", "Invalid ID format: {}", id_str);
         return HttpResponse::bad_request(b"Invalid ID format", vec![]).build();
     };
     let user_id = user_principal.to_text();
@@ -76,10 +160,12 @@ pub fn list_todo_items_handler(_req: &HttpRequest, _params: &Params) -> HttpResp
 }
 
 pub fn create_todo_item_handler(req: &HttpRequest, _params: &Params) -> HttpResponse<'static> {
-    ic_cdk::println!("[create_todo_item_handler] Processing request: {:?}", req);
     let user_principal = msg_caller();
 
-    let req_body: CreateTodoItemRequest = json_decode(req.body());
+    let req_body: CreateTodoItemRequest = match json_decode(req.body()) {
+        Ok(req_body) => req_body,
+        Err(response) => return response,
+    };
 
     let id = NEXT_TODO_ID.with_borrow_mut(|f| {
         let id = *f;
@@ -101,91 +187,117 @@ pub fn create_todo_item_handler(req: &HttpRequest, _params: &Params) -> HttpResp
     CreateTodoItemResponse::created(todo_item)
 }
 
-pub fn update_todo_item_handler(req: &HttpRequest, params: &Params) -> HttpResponse<'static> {
-    ic_cdk::println!("[update_todo_item_handler] Starting handler");
-    ic_cdk::println!(
-        "[update_todo_item_handler] Method: {}, Path: {}",
-        req.method(),
-        req.get_path().unwrap_or(String::from("unknown"))
-    );
-    ic_cdk::println!("[update_todo_item_handler] All Params: {:?}", params);
-
-    let user_principal = msg_caller();
-    ic_cdk::println!(
-        "[update_todo_item_handler] User principal: {}",
-        user_principal.to_text()
-    );
-
-    // Parse the request body
-    let req_body_result =
-        std::panic::catch_unwind(|| json_decode::<UpdateTodoItemRequest>(req.body()));
-    if req_body_result.is_err() {
-        ic_cdk::println!("[update_todo_item_handler] Failed to parse request body");
-        return HttpResponse::bad_request(b"Invalid request body", vec![]).build();
+pub fn replace_todo_item_handler(req: &HttpRequest, params: &Params) -> HttpResponse<'static> {
+    if content_type(req).as_deref() != Some(JSON_CONTENT_TYPE) {
+        return ErrorResponse::unsupported_media_type(&[JSON_CONTENT_TYPE]);
     }
-    let req_body = req_body_result.unwrap();
-    ic_cdk::println!("[update_todo_item_handler] Request body: {:?}", req_body);
 
-    // Parse the ID parameter - update this to use "id" in curly braces per matchit docs
-    let id_param = params.get("id");
-    ic_cdk::println!("[update_todo_item_handler] ID parameter: {:?}", id_param);
+    let req_body: ReplaceTodoItemRequest = match json_decode(req.body()) {
+        Ok(req_body) => req_body,
+        Err(response) => {
+            log_warn!(target: "replace_todo_item_handler", "Failed to parse request body");
+            return response;
+        }
+    };
+    log_debug!(target: "replace_todo_item_handler", "Request body: {:?}", req_body);
 
-    if id_param.is_none() {
-        ic_cdk::println!("[update_todo_item_handler] Missing ID parameter");
-        return HttpResponse::bad_request(b"Missing ID parameter", vec![]).build();
-    }
+    update_todo_item(params, |item| {
+        item.title = req_body.title;
+        item.completed = req_body.completed;
 
-    let id_parse_result = id_param.unwrap().parse::<u32>();
-    if id_parse_result.is_err() {
-        ic_cdk::println!(
-            "[update_todo_item_handler] Invalid ID format: {}",
-            id_param.unwrap()
-        );
-        return HttpResponse::bad_request(b"Invalid ID format", vec![]).build();
-    }
+        Ok(())
+    })
+}
 
-    let id = id_parse_result.unwrap();
-    ic_cdk::println!("[update_todo_item_handler] Todo ID: {}", id);
+/// Applies a JSON Merge Patch ([RFC 7396](https://www.rfc-editor.org/rfc/rfc7396))
+/// or a JSON Patch ([RFC 6902](https://www.rfc-editor.org/rfc/rfc6902)) to a todo item,
+/// depending on the content type of the request.
+pub fn patch_todo_item_handler(req: &HttpRequest, params: &Params) -> HttpResponse<'static> {
+    let patch = match content_type(req).as_deref() {
+        Some(MERGE_PATCH_CONTENT_TYPE) => json_decode(req.body()).map(TodoPatch::Merge),
+        Some(JSON_PATCH_CONTENT_TYPE) => json_decode(req.body()).map(TodoPatch::Json),
+        _ => {
+            let mut response = ErrorResponse::unsupported_media_type(PATCH_CONTENT_TYPES);
+            response.add_header(("accept-patch".to_string(), PATCH_CONTENT_TYPES.join(", ")));
+
+            return response;
+        }
+    };
+    let patch = match patch {
+        Ok(patch) => patch,
+        Err(response) => {
+            log_warn!(target: "patch_todo_item_handler", "Failed to parse request body");
+            return response;
+        }
+    };
+    log_debug!(target: "patch_todo_item_handler", "Patch: {:?}", patch);
 
-    let mut all_todos = todos().lock().unwrap();
-    let user_todos = all_todos.get_mut(&user_principal.to_text());
+    update_todo_item(params, |item| {
+        let mut document = serde_json::to_value(&*item).expect("Failed to serialize todo item");
 
-    if user_todos.is_none() {
-        ic_cdk::println!(
-            "[update_todo_item_handler] No todos found for user: {}",
-            user_principal.to_text()
-        );
-        return HttpResponse::not_found(b"Todo item not found", vec![]).build();
-    }
+        match patch {
+            TodoPatch::Merge(merge_patch) => json_patch::merge(&mut document, &merge_patch),
+            TodoPatch::Json(json_patch) => json_patch::patch(&mut document, &json_patch.0)
+                .map_err(|err| ErrorResponse::unprocessable_entity(err.to_string()))?,
+        }
 
-    let todo_item = user_todos.unwrap().get_mut(&id);
+        let patched_item: TodoItem = serde_json::from_value(document).map_err(|err| {
+            ErrorResponse::unprocessable_entity(format!("Invalid todo item: {err}"))
+        })?;
+        if patched_item.id != item.id {
+            return Err(ErrorResponse::unprocessable_entity(
+                "The ID of a todo item can't be changed".to_string(),
+            ));
+        }
 
-    if todo_item.is_none() {
-        ic_cdk::println!("[update_todo_item_handler] Todo with ID {} not found", id);
-        return HttpResponse::not_found(b"Todo item not found", vec![]).build();
-    }
+        *item = patched_item;
 
-    let item = todo_item.unwrap();
+        Ok(())
+    })
+}
 
-    if let Some(title) = req_body.title {
-        item.title = title;
-    }
+#[derive(Debug)]
+enum TodoPatch {
+    Merge(Value),
+    Json(json_patch::Patch),
+}
 
-    if let Some(completed) = req_body.completed {
-        item.completed = completed;
+/// Looks up the caller's todo item with the ID from the route parameters,
+/// and applies `update` to it.
+fn update_todo_item(
+    params: &Params,
+    update: impl FnOnce(&mut TodoItem) -> Result<(), HttpResponse<'static>>,
+) -> HttpResponse<'static> {
+    let Some(id_param) = params.get("id") else {
+        log_warn!(target: "update_todo_item", "Missing ID parameter");
+        return HttpResponse::bad_request(b"Missing ID parameter", vec![]).build();
+    };
+    let Ok(id) = id_param.parse::<u32>() else {
+        log_warn!(target: "update_todo_item", "Invalid ID format: {}", id_param);
+        return HttpResponse::bad_request(b"Invalid ID format", vec![]).build();
+    };
+
+    let user_principal = msg_caller();
+    log_debug!(target: "update_todo_item", "User principal: {}", user_principal.to_text());
+
+    let mut all_todos = todos().lock().unwrap();
+    let Some(item) = all_todos
+        .get_mut(&user_principal.to_text())
+        .and_then(|user_todos| user_todos.get_mut(&id))
+    else {
+        log_warn!(target: "update_todo_item", "Todo with ID {} not found", id);
+        return HttpResponse::not_found(b"Todo item not found", vec![]).build();
+    };
+
+    if let Err(response) = update(item) {
+        return response;
     }
 
-    UpdateTodoItemResponse::ok(())
+    UpdateTodoItemResponse::ok(item.clone())
 }
 
-pub fn delete_todo_item_handler(req: &HttpRequest, params: &Params) -> HttpResponse<'static> {
-    ic_cdk::println!("[delete_todo_item_handler] Starting handler");
-    ic_cdk::println!(
-        "[delete_todo_item_handler] Method: {}, Path: {}",
-        req.method(),
-        req.get_path().unwrap_or(String::from("unknown"))
-    );
-    ic_cdk::println!("[delete_todo_item_handler] All Params: {:?}", params);
+pub fn delete_todo_item_handler(_req: &HttpRequest, params: &Params) -> HttpResponse<'static> {
+    log_debug!(target: "delete_todo_item_handler", "All Params: {:?}", params);
 
     let user_principal = msg_caller();
 
@@ -198,3 +310,179 @@ pub fn delete_todo_item_handler(req: &HttpRequest, params: &Params) -> HttpRespo
 
     DeleteTodoItemResponse::ok(())
 }
+
+pub fn batch_todo_items_handler(req: &HttpRequest, _params: &Params) -> HttpResponse<'static> {
+    let req_body: BatchTodoItemsRequest = match json_decode(req.body()) {
+        Ok(req_body) => req_body,
+        Err(response) => return response,
+    };
+
+    if req_body.operations.len() > MAX_BATCH_OPERATIONS {
+        return ErrorResponse::bad_request(format!(
+            "A batch can contain at most {MAX_BATCH_OPERATIONS} operations"
+        ));
+    }
+
+    let snapshot = TodoSnapshot::take(msg_caller().to_text(), &req_body.operations);
+    let router = get_api_router();
+    let mut results = Vec::with_capacity(req_body.operations.len());
+    let mut committed = true;
+
+    // Each operation goes through the same route handler as its single item counterpart,
+    // so that validation behaves the same. Operations after a failed one are skipped.
+    for operation in req_body.operations {
+        if !committed {
+            results.push(BatchOperationResult {
+                status: StatusCode::FAILED_DEPENDENCY.as_u16(),
+                body: Value::Null,
+            });
+            continue;
+        }
+
+        let response = router.dispatch(&batch_operation_request(operation));
+        committed = response.status_code().is_success();
+        results.push(batch_operation_result(&response));
+    }
+
+    if !committed {
+        log_warn!(target: "batch_todo_items_handler", "Batch failed, rolling back");
+        snapshot.restore();
+    }
+
+    BatchTodoItemsResponse::ok(BatchTodoItemsResponseBody { committed, results })
+}
+
+fn batch_operation_request(operation: BatchOperation) -> HttpRequest<'static> {
+    let (method, url, content_type, body) = match operation {
+        BatchOperation::Create(create) => (
+            Method::POST,
+            "/api/todos".to_string(),
+            JSON_CONTENT_TYPE,
+            json_encode(&create),
+        ),
+        BatchOperation::Update { id, changes } => (
+            Method::PATCH,
+            format!("/api/todos/{id}"),
+            MERGE_PATCH_CONTENT_TYPE,
+            json_encode(&changes),
+        ),
+        BatchOperation::Delete { id } => (
+            Method::DELETE,
+            format!("/api/todos/{id}"),
+            JSON_CONTENT_TYPE,
+            vec![],
+        ),
+    };
+
+    HttpRequest::builder()
+        .with_method(method)
+        .with_url(url)
+        .with_headers(vec![("content-type".to_string(), content_type.to_string())])
+        .with_body(body)
+        .build()
+}
+
+fn batch_operation_result(response: &HttpResponse) -> BatchOperationResult {
+    let body = serde_json::from_slice(response.body())
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(response.body()).into_owned()));
+
+    BatchOperationResult {
+        status: response.status_code().as_u16(),
+        body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::todo_types::UpdateTodoItemRequest;
+    use super::*;
+
+    fn todo_item(id: u32, title: &str) -> TodoItem {
+        TodoItem {
+            id,
+            title: title.to_string(),
+            completed: false,
+        }
+    }
+
+    #[test]
+    fn restore_rolls_back_only_the_touched_todos() {
+        let user_id = "rollback-user".to_string();
+        let other_user_id = "rollback-other-user".to_string();
+        NEXT_TODO_ID.set(2);
+        {
+            let mut all_todos = todos().lock().unwrap();
+            all_todos.insert(
+                user_id.clone(),
+                TodoMap::from([(0, todo_item(0, "first")), (1, todo_item(1, "second"))]),
+            );
+            all_todos.insert(
+                other_user_id.clone(),
+                TodoMap::from([(5, todo_item(5, "other"))]),
+            );
+        }
+
+        let operations = [
+            BatchOperation::Create(CreateTodoItemRequest {
+                title: "created".to_string(),
+            }),
+            BatchOperation::Update {
+                id: 0,
+                changes: UpdateTodoItemRequest {
+                    title: Some("updated".to_string()),
+                    completed: None,
+                },
+            },
+            BatchOperation::Delete { id: 1 },
+        ];
+        let snapshot = TodoSnapshot::take(user_id.clone(), &operations);
+
+        // Apply the batch, and a change to another user that happens meanwhile
+        NEXT_TODO_ID.set(3);
+        {
+            let mut all_todos = todos().lock().unwrap();
+            let user_todos = all_todos.get_mut(&user_id).unwrap();
+            user_todos.insert(2, todo_item(2, "created"));
+            user_todos.insert(0, todo_item(0, "updated"));
+            user_todos.remove(&1);
+            all_todos
+                .get_mut(&other_user_id)
+                .unwrap()
+                .insert(6, todo_item(6, "other new"));
+        }
+
+        snapshot.restore();
+
+        assert_eq!(NEXT_TODO_ID.with_borrow(|id| *id), 2);
+        let all_todos = todos().lock().unwrap();
+        let user_todos = &all_todos[&user_id];
+        assert_eq!(user_todos.len(), 2);
+        assert_eq!(user_todos[&0].title, "first");
+        assert_eq!(user_todos[&1].title, "second");
+        assert_eq!(all_todos[&other_user_id].len(), 2);
+    }
+
+    #[test]
+    fn restore_removes_the_todos_of_a_new_user() {
+        let user_id = "rollback-new-user".to_string();
+        let operations = [BatchOperation::Create(CreateTodoItemRequest {
+            title: "created".to_string(),
+        })];
+        let snapshot = TodoSnapshot::take(user_id.clone(), &operations);
+
+        let id = NEXT_TODO_ID.with_borrow_mut(|next_id| {
+            *next_id += 1;
+            *next_id - 1
+        });
+        todos()
+            .lock()
+            .unwrap()
+            .entry(user_id.clone())
+            .or_default()
+            .insert(id, todo_item(id, "created"));
+
+        snapshot.restore();
+
+        assert!(!todos().lock().unwrap().contains_key(&user_id));
+    }
+}
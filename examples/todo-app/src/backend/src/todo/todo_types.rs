@@ -3,13 +3,14 @@ use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize, CandidType)]
+#[serde(deny_unknown_fields)]
 pub struct TodoItem {
     pub id: u32,
     pub title: String,
     pub completed: bool,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateTodoItemRequest {
     pub title: String,
 }
@@ -17,12 +18,22 @@ pub struct CreateTodoItemRequest {
 pub type CreateTodoItemResponse = ApiResponse<TodoItem>;
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplaceTodoItemRequest {
+    pub title: String,
+    pub completed: bool,
+}
+
+/// The fields to change in a todo item, sent as a JSON Merge Patch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateTodoItemRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub completed: Option<bool>,
 }
 
-pub type UpdateTodoItemResponse = ApiResponse;
+pub type UpdateTodoItemResponse = ApiResponse<TodoItem>;
 
 pub type DeleteTodoItemResponse = ApiResponse;
 
@@ -35,3 +46,44 @@ pub struct ListTodosResponseBody {
 }
 
 pub type ListTodosResponse = ApiResponse<ListTodosResponseBody>;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOperation {
+    Create(CreateTodoItemRequest),
+    Update {
+        id: u32,
+        #[serde(flatten)]
+        changes: UpdateTodoItemRequest,
+    },
+    Delete {
+        id: u32,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchTodoItemsRequest {
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchOperationResult {
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchTodoItemsResponseBody {
+    /// Whether the operations were applied. If any operation fails, none of them are.
+    pub committed: bool,
+    pub results: Vec<BatchOperationResult>,
+}
+
+pub type BatchTodoItemsResponse = ApiResponse<BatchTodoItemsResponseBody>;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TodoCounts {
+    pub users: usize,
+    pub items: usize,
+    pub completed_items: usize,
+}
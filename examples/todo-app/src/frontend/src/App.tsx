@@ -157,9 +157,9 @@ async function toggleTodoCompleted(
   console.log(`Toggling todo #${id} completed status from ${completed} to ${!completed}`);
   try {
     const req = new Request(`/api/todos/${id}`, {
-      method: 'PUT',
+      method: 'PATCH',
       headers: {
-        'Content-Type': 'application/json',
+        'Content-Type': 'application/merge-patch+json',
       },
       body: JSON.stringify({
         completed: !completed,
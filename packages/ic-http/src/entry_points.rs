@@ -0,0 +1,197 @@
+use ic_http_certification::{HttpRequest, HttpResponse, HttpUpdateRequest};
+
+use crate::UPGRADE_HEADER_NAME;
+
+/// Exposes the same handlers on the bHTTP entry points, `http_request_v2` and
+/// `http_request_update_v2`, and on the Candid entry points of the classic HTTP gateway
+/// interface, `http_request` and `http_request_update`.
+///
+/// The handlers take an [HttpRequest] and return an [HttpResponse]. A query handler asks
+/// for an update call with [HttpResponse::upgrade], which is sent as the `ic-upgrade`
+/// header in bHTTP and as the `upgrade` field in Candid.
+///
+/// The chunks of streamed bHTTP responses after the first one are returned by
+/// `http_request_streaming_callback_v2`, which calls the query handler again. Candid
+/// responses are never streamed.
+///
+/// The entry points only refer to this crate, so the canister doesn't need to depend on
+/// `ic-cdk` or `candid` for them.
+///
+/// ```ignore
+/// ic_http::http_entry_points!(query = http_query, update = http_update);
+/// ```
+#[macro_export]
+macro_rules! http_entry_points {
+    (query = $query:path, update = $update:path $(,)?) => {
+        #[unsafe(export_name = "canister_query http_request_v2")]
+        extern "C" fn __ic_http_request_v2() {
+            $crate::__private::bhttp_query($query)
+        }
+
+        #[unsafe(export_name = "canister_update http_request_update_v2")]
+        extern "C" fn __ic_http_request_update_v2() {
+            $crate::__private::bhttp_update($update)
+        }
+
+        #[unsafe(export_name = "canister_query http_request_streaming_callback_v2")]
+        extern "C" fn __ic_http_request_streaming_callback_v2() {
+            $crate::__private::bhttp_query($query)
+        }
+
+        #[unsafe(export_name = "canister_query http_request")]
+        extern "C" fn __ic_http_request() {
+            $crate::__private::candid_query($query)
+        }
+
+        #[unsafe(export_name = "canister_update http_request_update")]
+        extern "C" fn __ic_http_request_update() {
+            $crate::__private::candid_update($update)
+        }
+    };
+}
+
+/// The bodies of the entry points generated by [http_entry_points].
+#[doc(hidden)]
+pub mod __private {
+    use ic_cdk::{
+        api::{msg_arg_data, msg_reply},
+        futures::{in_executor_context, in_query_executor_context},
+        trap,
+    };
+    use ic_http_certification::{HttpRequest, HttpResponse, HttpUpdateRequest};
+
+    use crate::{decode_args, encode_result, from_update_request, into_candid_response};
+
+    pub fn bhttp_query(handler: impl FnOnce(HttpRequest<'static>) -> HttpResponse<'static>) {
+        in_query_executor_context(|| {
+            msg_reply(encode_result(handler(decode_args(msg_arg_data()))));
+        });
+    }
+
+    pub fn bhttp_update(handler: impl FnOnce(HttpRequest<'static>) -> HttpResponse<'static>) {
+        in_executor_context(|| {
+            msg_reply(encode_result(handler(decode_args(msg_arg_data()))));
+        });
+    }
+
+    pub fn candid_query(handler: impl FnOnce(HttpRequest<'static>) -> HttpResponse<'static>) {
+        in_query_executor_context(|| {
+            let req = decode_candid::<HttpRequest<'static>>();
+            reply_candid(into_candid_response(handler(req)));
+        });
+    }
+
+    pub fn candid_update(handler: impl FnOnce(HttpRequest<'static>) -> HttpResponse<'static>) {
+        in_executor_context(|| {
+            let req = decode_candid::<HttpUpdateRequest<'static>>();
+            reply_candid(into_candid_response(handler(from_update_request(req))));
+        });
+    }
+
+    fn decode_candid<T: candid::CandidType + for<'de> serde::Deserialize<'de>>() -> T {
+        candid::decode_one(&msg_arg_data())
+            .unwrap_or_else(|e| trap(format!("Failed to decode the request: {e}")))
+    }
+
+    fn reply_candid(res: HttpResponse) {
+        match candid::encode_one(res) {
+            Ok(bytes) => msg_reply(bytes),
+            Err(e) => trap(format!("Failed to encode the response: {e}")),
+        }
+    }
+}
+
+/// Converts the argument of `http_request_update`, which has no `certificate_version`,
+/// into the request taken by the handlers.
+pub fn from_update_request<'a>(req: HttpUpdateRequest) -> HttpRequest<'a> {
+    HttpRequest::builder()
+        .with_method(req.method().clone())
+        .with_url(req.url().to_string())
+        .with_headers(req.headers().to_vec())
+        .with_body(req.body().to_vec())
+        .build()
+}
+
+/// Turns an `ic-upgrade: true` header set by a handler into the `upgrade` field, since
+/// Candid gateways ignore the header.
+pub fn into_candid_response(res: HttpResponse) -> HttpResponse {
+    let upgrade_header = res
+        .headers()
+        .iter()
+        .any(|(name, value)| name.eq_ignore_ascii_case(UPGRADE_HEADER_NAME) && value == "true");
+    if !upgrade_header {
+        return res;
+    }
+
+    let headers = res
+        .headers()
+        .iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case(UPGRADE_HEADER_NAME))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    HttpResponse::builder()
+        .with_status_code(res.status_code())
+        .with_headers(headers)
+        .with_body(res.body().to_vec())
+        .with_upgrade(true)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_http_certification::{Method, StatusCode};
+
+    use super::*;
+    use crate::{decode_args, encode_request};
+
+    #[test]
+    fn upgrade_header_becomes_the_upgrade_field() {
+        let res = HttpResponse::builder()
+            .with_status_code(StatusCode::OK)
+            .with_headers(vec![
+                ("content-type".to_string(), "text/plain".to_string()),
+                ("IC-Upgrade".to_string(), "true".to_string()),
+            ])
+            .with_body(b"hello".to_vec())
+            .build();
+
+        let res = into_candid_response(res);
+
+        assert_eq!(res.upgrade(), Some(true));
+        assert_eq!(
+            res.headers(),
+            [("content-type".to_string(), "text/plain".to_string())]
+        );
+        assert_eq!(res.status_code(), StatusCode::OK);
+        assert_eq!(res.body(), b"hello");
+    }
+
+    #[test]
+    fn response_without_upgrade_header_is_unchanged() {
+        let res = HttpResponse::builder()
+            .with_status_code(StatusCode::NOT_FOUND)
+            .with_headers(vec![(UPGRADE_HEADER_NAME.to_string(), "false".to_string())])
+            .build();
+
+        assert_eq!(into_candid_response(res.clone()), res);
+    }
+
+    #[test]
+    fn update_request_matches_the_decoded_bhttp_request() {
+        let req = HttpRequest::builder()
+            .with_method(Method::POST)
+            .with_url("/todos?done=false")
+            .with_headers(vec![
+                ("content-type".to_string(), "application/json".to_string()),
+                ("x-request-id".to_string(), "42".to_string()),
+            ])
+            .with_body(br#"{"title":"test"}"#.to_vec())
+            .build();
+
+        assert_eq!(
+            from_update_request(HttpUpdateRequest::from(req.clone())),
+            decode_args(encode_request(&req))
+        );
+    }
+}
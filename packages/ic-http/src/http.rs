@@ -1,7 +1,31 @@
-use std::{io::Cursor, str::FromStr};
+use std::{fmt, io::Cursor, str::FromStr};
 
-use bhttp::{Message, Mode, StatusCode};
-use ic_http_certification::{HttpRequest, HttpResponse, Method};
+use bhttp::{ControlData, Message, Mode, StatusCode};
+use ic_http_certification::{HttpRequest, HttpResponse, Method, StatusCode as HttpStatusCode};
+
+use crate::streaming::{
+    STREAMING_TOKEN_HEADER_NAME, chunk_range, set_requested_chunk, take_requested_chunk,
+};
+
+/// Header of bHTTP responses asking for a query to be retried as an update call, in place
+/// of the `upgrade` field of Candid responses.
+pub const UPGRADE_HEADER_NAME: &str = "ic-upgrade";
+
+/// Scheme of the bHTTP requests whose URL is only a path, the one of the local replica
+/// gateway without TLS. Signed requests must be encoded with the scheme they are sent with.
+pub const BHTTP_SCHEME: &str = "http";
+
+/// Error of [decode_result].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError(String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to decode the bHTTP response: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
 
 pub fn decode_args<'a>(bytes: Vec<u8>) -> HttpRequest<'a> {
     let mut cursor = Cursor::new(bytes);
@@ -10,7 +34,7 @@ pub fn decode_args<'a>(bytes: Vec<u8>) -> HttpRequest<'a> {
 
     let control = msg.control();
     let (method_bytes, path_bytes) = match control {
-        bhttp::ControlData::Request {
+        ControlData::Request {
             method,
             scheme: _,
             authority: _,
@@ -32,6 +56,7 @@ pub fn decode_args<'a>(bytes: Vec<u8>) -> HttpRequest<'a> {
             )
         })
         .collect();
+    set_requested_chunk(&headers);
 
     HttpRequest::builder()
         .with_url(path_str.to_string())
@@ -41,21 +66,134 @@ pub fn decode_args<'a>(bytes: Vec<u8>) -> HttpRequest<'a> {
         .build()
 }
 
+/// Encodes the response of a bHTTP entry point.
+///
+/// If the request accepted a streamed response and the body is above [crate::MAX_CHUNK_SIZE],
+/// only the requested chunk of the body is encoded, along with the token of the next one.
+/// The headers are only sent with the first chunk. An invalid or out of range streaming
+/// token gets a `400` response instead.
 pub fn encode_result(res: HttpResponse) -> Vec<u8> {
+    let body_len = res.body().len();
+    let chunk = take_requested_chunk(body_len)
+        .and_then(|chunk| chunk.map(|chunk| chunk_range(body_len, chunk)).transpose());
+
+    match chunk {
+        Ok(chunk) => encode_response(&res, chunk),
+        Err(e) => {
+            let res = HttpResponse::builder()
+                .with_status_code(HttpStatusCode::BAD_REQUEST)
+                .with_body(e.into_bytes())
+                .build();
+            encode_response(&res, None)
+        }
+    }
+}
+
+/// Encodes the whole response, or a chunk of its body given by [chunk_range].
+fn encode_response(res: &HttpResponse, chunk: Option<(usize, usize, Option<String>)>) -> Vec<u8> {
     let status = StatusCode::try_from(res.status_code().as_u16()).unwrap();
     let mut msg = Message::response(status);
 
-    for (header_name, header_value) in res.headers() {
-        msg.put_header(header_name.as_bytes(), header_value.as_bytes());
+    if chunk.as_ref().is_none_or(|(start, _, _)| *start == 0) {
+        for (header_name, header_value) in res.headers() {
+            msg.put_header(header_name.as_bytes(), header_value.as_bytes());
+        }
+
+        if res.upgrade().unwrap_or(false) {
+            msg.put_header(UPGRADE_HEADER_NAME.as_bytes(), b"true");
+        }
     }
 
-    if res.upgrade().unwrap_or(false) {
-        msg.put_header(b"ic-upgrade", b"true");
+    match chunk {
+        Some((start, end, next_token)) => {
+            if let Some(next_token) = next_token {
+                msg.put_header(
+                    STREAMING_TOKEN_HEADER_NAME.as_bytes(),
+                    next_token.as_bytes(),
+                );
+            }
+            msg.write_content(&res.body()[start..end]);
+        }
+        None => msg.write_content(res.body()),
     }
 
-    msg.write_content(res.body());
+    let mut encoded = Vec::new();
+    msg.write_bhttp(Mode::KnownLength, &mut encoded).unwrap();
+    encoded
+}
+
+/// Encodes a request the way [decode_args] expects it, e.g. to call `http_request_v2`
+/// from a client or another canister.
+///
+/// The scheme and the authority are taken from the URL if it's absolute, otherwise the
+/// scheme is [BHTTP_SCHEME] and the authority is empty.
+pub fn encode_request(req: &HttpRequest) -> Vec<u8> {
+    let (scheme, authority, path) = match req.url().split_once("://") {
+        Some((scheme, rest)) => {
+            let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+            let path = if path.starts_with('/') {
+                path.to_string()
+            } else {
+                format!("/{path}")
+            };
+            (scheme, authority, path)
+        }
+        None => (BHTTP_SCHEME, "", req.url().to_string()),
+    };
+    let mut msg = Message::request(
+        req.method().as_str().as_bytes().to_vec(),
+        scheme.as_bytes().to_vec(),
+        authority.as_bytes().to_vec(),
+        path.into_bytes(),
+    );
+
+    for (header_name, header_value) in req.headers() {
+        msg.put_header(header_name.as_bytes(), header_value.as_bytes());
+    }
+
+    msg.write_content(req.body());
 
     let mut encoded = Vec::new();
     msg.write_bhttp(Mode::KnownLength, &mut encoded).unwrap();
     encoded
 }
+
+/// Decodes a response encoded by [encode_result]. The `ic-upgrade` header is turned back
+/// into the `upgrade` field.
+pub fn decode_result<'a>(bytes: Vec<u8>) -> Result<HttpResponse<'a>, DecodeError> {
+    let mut cursor = Cursor::new(bytes);
+    let msg = Message::read_bhttp(&mut cursor).map_err(|e| DecodeError(e.to_string()))?;
+
+    let status = match msg.control() {
+        ControlData::Response(status) => status.code(),
+        _ => {
+            return Err(DecodeError(
+                "expected a response, got a request".to_string(),
+            ));
+        }
+    };
+    let status = HttpStatusCode::from_u16(status)
+        .map_err(|_| DecodeError(format!("invalid status code {status}")))?;
+
+    let mut upgrade = false;
+    let headers: Vec<(String, String)> = msg
+        .header()
+        .iter()
+        .filter_map(|field| {
+            let name = String::from_utf8_lossy(field.name()).to_string();
+            let value = String::from_utf8_lossy(field.value()).to_string();
+            if name.eq_ignore_ascii_case(UPGRADE_HEADER_NAME) {
+                upgrade = value == "true";
+                return None;
+            }
+            Some((name, value))
+        })
+        .collect();
+
+    Ok(HttpResponse::builder()
+        .with_status_code(status)
+        .with_headers(headers)
+        .with_body(msg.content().to_vec())
+        .with_upgrade(upgrade)
+        .build())
+}
@@ -1,3 +1,9 @@
+mod entry_points;
 mod http;
+mod logger;
+mod streaming;
 
+pub use entry_points::*;
 pub use http::*;
+pub use logger::*;
+pub use streaming::*;
@@ -0,0 +1,355 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+};
+
+use ic_http_certification::{HeaderField, HttpRequest};
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of log entries kept in memory.
+const LOG_BUFFER_CAPACITY: usize = 256;
+
+const REDACTED_HEADER_VALUE: &str = "[REDACTED]";
+const SIGNATURE_HEADER_PREFIX: &str = "signature";
+const AUTHORIZATION_HEADER_NAME: &str = "authorization";
+const SIGNATURE_INPUT_HEADER_NAME: &str = "signature-input";
+const NONCE_COMPONENT_PREFIX: &str = "nonce=";
+
+thread_local! {
+    static LOG_LEVEL: Cell<LogLevel> = const { Cell::new(LogLevel::Info) };
+    static LOG_BUFFER: RefCell<VecDeque<LogEntry>> = RefCell::new(VecDeque::with_capacity(LOG_BUFFER_CAPACITY));
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    static NEXT_REQUEST_SEQ: Cell<u64> = const { Cell::new(0) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        };
+
+        f.write_str(level)
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("Unknown log level: {s}")),
+        }
+    }
+}
+
+/// A single structured log line, printed as JSON and kept in the in-memory ring buffer.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub timestamp_ns: u64,
+    pub level: LogLevel,
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<RequestLog>,
+}
+
+/// The loggable parts of an [HttpRequest], with sensitive headers redacted.
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestLog {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderField>,
+}
+
+impl From<&HttpRequest<'_>> for RequestLog {
+    fn from(req: &HttpRequest) -> Self {
+        Self {
+            method: req.method().to_string(),
+            url: req.url().to_string(),
+            headers: redact_headers(req.headers()),
+        }
+    }
+}
+
+pub fn log_level() -> LogLevel {
+    LOG_LEVEL.get()
+}
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.set(level);
+}
+
+/// Returns the most recent log entries, oldest first.
+///
+/// Entries written during query calls are not kept, as their state changes are discarded.
+pub fn recent_logs() -> Vec<LogEntry> {
+    LOG_BUFFER.with_borrow(|buffer| buffer.iter().cloned().collect())
+}
+
+/// Starts the logging context of a request and logs it at [LogLevel::Debug].
+///
+/// The request id is taken from the signature nonce if the request is signed,
+/// otherwise a canister local id is generated. Every log written until the next
+/// call to this function is tagged with the returned request id.
+pub fn begin_request(req: &HttpRequest) -> String {
+    let request_id = signature_nonce(req).unwrap_or_else(next_local_request_id);
+    REQUEST_ID.set(Some(request_id.clone()));
+
+    if log_enabled(LogLevel::Debug) {
+        push_entry(new_entry(
+            LogLevel::Debug,
+            "ic_http::request",
+            "Received request".to_string(),
+            Some(RequestLog::from(req)),
+        ));
+    }
+
+    request_id
+}
+
+/// Whether entries at this level are written, checked by the `log_*!` macros before
+/// formatting the message.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level <= log_level()
+}
+
+pub fn write_log(level: LogLevel, target: &str, message: String) {
+    if !log_enabled(level) {
+        return;
+    }
+
+    push_entry(new_entry(level, target, message, None));
+}
+
+/// Replaces the value of `signature*` and `authorization` headers.
+pub fn redact_headers(headers: &[HeaderField]) -> Vec<HeaderField> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name_lower = name.to_ascii_lowercase();
+            let is_sensitive = name_lower.starts_with(SIGNATURE_HEADER_PREFIX)
+                || name_lower == AUTHORIZATION_HEADER_NAME;
+
+            if is_sensitive {
+                (name.clone(), REDACTED_HEADER_VALUE.to_string())
+            } else {
+                (name.clone(), value.clone())
+            }
+        })
+        .collect()
+}
+
+fn signature_nonce(req: &HttpRequest) -> Option<String> {
+    let (_, signature_input) = req
+        .headers()
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(SIGNATURE_INPUT_HEADER_NAME))?;
+
+    let start = signature_input.find(NONCE_COMPONENT_PREFIX)? + NONCE_COMPONENT_PREFIX.len();
+    let nonce = &signature_input[start..];
+    let end = nonce.find([';', ')', ',']).unwrap_or(nonce.len());
+
+    Some(nonce[..end].to_string()).filter(|nonce| !nonce.is_empty())
+}
+
+fn next_local_request_id() -> String {
+    let seq = NEXT_REQUEST_SEQ.get();
+    NEXT_REQUEST_SEQ.set(seq + 1);
+
+    format!("{:x}-{seq}", ic_cdk::api::time())
+}
+
+fn new_entry(
+    level: LogLevel,
+    target: &str,
+    message: String,
+    request: Option<RequestLog>,
+) -> LogEntry {
+    LogEntry {
+        timestamp_ns: ic_cdk::api::time(),
+        level,
+        target: target.to_string(),
+        request_id: REQUEST_ID.with_borrow(Clone::clone),
+        message,
+        request,
+    }
+}
+
+fn push_entry(entry: LogEntry) {
+    match serde_json::to_string(&entry) {
+        Ok(line) => ic_cdk::println!("{}", line),
+        Err(err) => ic_cdk::println!("Failed to serialize log entry: {}", err),
+    }
+
+    LOG_BUFFER.with_borrow_mut(|buffer| {
+        if buffer.len() == LOG_BUFFER_CAPACITY {
+            buffer.pop_front();
+        }
+        buffer.push_back(entry);
+    });
+}
+
+#[macro_export]
+macro_rules! log_error {
+    (target: $target:expr, $($arg:tt)+) => {
+        if $crate::log_enabled($crate::LogLevel::Error) {
+            $crate::write_log($crate::LogLevel::Error, $target, format!($($arg)+))
+        }
+    };
+    ($($arg:tt)+) => {
+        if $crate::log_enabled($crate::LogLevel::Error) {
+            $crate::write_log($crate::LogLevel::Error, module_path!(), format!($($arg)+))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    (target: $target:expr, $($arg:tt)+) => {
+        if $crate::log_enabled($crate::LogLevel::Warn) {
+            $crate::write_log($crate::LogLevel::Warn, $target, format!($($arg)+))
+        }
+    };
+    ($($arg:tt)+) => {
+        if $crate::log_enabled($crate::LogLevel::Warn) {
+            $crate::write_log($crate::LogLevel::Warn, module_path!(), format!($($arg)+))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    (target: $target:expr, $($arg:tt)+) => {
+        if $crate::log_enabled($crate::LogLevel::Info) {
+            $crate::write_log($crate::LogLevel::Info, $target, format!($($arg)+))
+        }
+    };
+    ($($arg:tt)+) => {
+        if $crate::log_enabled($crate::LogLevel::Info) {
+            $crate::write_log($crate::LogLevel::Info, module_path!(), format!($($arg)+))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    (target: $target:expr, $($arg:tt)+) => {
+        if $crate::log_enabled($crate::LogLevel::Debug) {
+            $crate::write_log($crate::LogLevel::Debug, $target, format!($($arg)+))
+        }
+    };
+    ($($arg:tt)+) => {
+        if $crate::log_enabled($crate::LogLevel::Debug) {
+            $crate::write_log($crate::LogLevel::Debug, module_path!(), format!($($arg)+))
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_http_certification::Method;
+
+    use super::*;
+
+    fn header(name: &str, value: &str) -> HeaderField {
+        (name.to_string(), value.to_string())
+    }
+
+    fn request_with_headers(headers: Vec<HeaderField>) -> HttpRequest<'static> {
+        HttpRequest::builder()
+            .with_method(Method::GET)
+            .with_url("/api/todos".to_string())
+            .with_headers(headers)
+            .build()
+    }
+
+    #[test]
+    fn redact_headers_redacts_signature_and_authorization_headers() {
+        let headers = vec![
+            header("Signature", "sig_call=:c2lnbmF0dXJl:"),
+            header("signature-input", "sig_call=(nonce=bm9uY2U=)"),
+            header("Signature-Key", "sig_call=:a2V5:"),
+            header("AUTHORIZATION", "Bearer token"),
+            header("content-type", "application/json"),
+        ];
+
+        assert_eq!(
+            redact_headers(&headers),
+            vec![
+                header("Signature", REDACTED_HEADER_VALUE),
+                header("signature-input", REDACTED_HEADER_VALUE),
+                header("Signature-Key", REDACTED_HEADER_VALUE),
+                header("AUTHORIZATION", REDACTED_HEADER_VALUE),
+                header("content-type", "application/json"),
+            ]
+        );
+    }
+
+    #[test]
+    fn redact_headers_keeps_similar_header_names() {
+        let headers = vec![
+            header("x-signature", "value"),
+            header("authorization-hint", "value"),
+        ];
+
+        assert_eq!(redact_headers(&headers), headers);
+    }
+
+    #[test]
+    fn signature_nonce_is_taken_from_the_signature_input() {
+        let req = request_with_headers(vec![header(
+            "Signature-Input",
+            "sig_call=(request_type=call;include_headers=accept,host;nonce=bm9u+Y2/U=);\
+             sig_read_state=(request_type=read_state;nonce=bm9u+Y2/U=)",
+        )]);
+
+        assert_eq!(signature_nonce(&req), Some("bm9u+Y2/U=".to_string()));
+    }
+
+    #[test]
+    fn signature_nonce_ends_at_the_closing_parenthesis() {
+        let req = request_with_headers(vec![header(
+            "signature-input",
+            "sig_query=(request_type=query;nonce=bm9uY2U=)",
+        )]);
+
+        assert_eq!(signature_nonce(&req), Some("bm9uY2U=".to_string()));
+    }
+
+    #[test]
+    fn signature_nonce_is_none_without_a_nonce() {
+        let unsigned = request_with_headers(vec![header("content-type", "text/plain")]);
+        let without_nonce = request_with_headers(vec![header(
+            "signature-input",
+            "sig_query=(request_type=query)",
+        )]);
+        let empty_nonce = request_with_headers(vec![header(
+            "signature-input",
+            "sig_query=(request_type=query;nonce=)",
+        )]);
+
+        assert_eq!(signature_nonce(&unsigned), None);
+        assert_eq!(signature_nonce(&without_nonce), None);
+        assert_eq!(signature_nonce(&empty_nonce), None);
+    }
+}
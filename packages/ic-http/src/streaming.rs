@@ -0,0 +1,272 @@
+use std::cell::RefCell;
+
+use ic_http_certification::{HeaderField, HttpRequest, HttpResponse};
+
+use crate::{DecodeError, decode_result, encode_request};
+
+/// Request header of clients that can reassemble streamed responses, with the value `true`.
+///
+/// ic-gateway never sends it, so the responses served through a gateway are still capped
+/// by the reply size limit. Streaming is for clients calling the canister with an agent.
+pub const ACCEPT_STREAMING_HEADER_NAME: &str = "ic-accept-streaming";
+
+/// Continuation token. On a response, more chunks follow and the request is to be sent to
+/// [STREAMING_CALLBACK_METHOD] with the token. On such a request, the chunk to return.
+pub const STREAMING_TOKEN_HEADER_NAME: &str = "ic-streaming-token";
+
+/// The query method returning the chunks after the first one.
+pub const STREAMING_CALLBACK_METHOD: &str = "http_request_streaming_callback_v2";
+
+/// Bodies above this size are streamed, leaving room for the headers below the reply
+/// size limit.
+pub const MAX_CHUNK_SIZE: usize = 1536 * 1024;
+
+thread_local! {
+    /// The chunk to reply with, if the caller accepts streamed responses, or the error of an
+    /// invalid streaming token.
+    static REQUESTED_CHUNK: RefCell<Option<Result<usize, String>>> = const { RefCell::new(None) };
+}
+
+/// Records whether the request accepts a streamed response, and which chunk it asks for.
+pub(crate) fn set_requested_chunk(headers: &[HeaderField]) {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+
+    let chunk = match header(STREAMING_TOKEN_HEADER_NAME) {
+        Some(token) => Some(
+            token
+                .parse()
+                .map_err(|_| format!("Invalid streaming token {token}")),
+        ),
+        None => (header(ACCEPT_STREAMING_HEADER_NAME) == Some("true")).then_some(Ok(0)),
+    };
+    REQUESTED_CHUNK.set(chunk);
+}
+
+/// The chunk to reply with, if the response is streamed, or the error of an invalid
+/// streaming token.
+///
+/// Update calls are never streamed, since [STREAMING_CALLBACK_METHOD] calls the query handler.
+pub(crate) fn take_requested_chunk(body_len: usize) -> Result<Option<usize>, String> {
+    let Some(chunk) = REQUESTED_CHUNK.take() else {
+        return Ok(None);
+    };
+    let chunk = chunk?;
+
+    Ok((body_len > MAX_CHUNK_SIZE && !in_replicated_execution()).then_some(chunk))
+}
+
+#[cfg(not(test))]
+fn in_replicated_execution() -> bool {
+    ic_cdk::api::in_replicated_execution()
+}
+
+/// Tests run as queries, outside of a canister.
+#[cfg(test)]
+fn in_replicated_execution() -> bool {
+    false
+}
+
+/// The bounds of a chunk in the body, and the token of the next chunk if any. Fails if the
+/// chunk is past the end of the body.
+pub(crate) fn chunk_range(
+    body_len: usize,
+    chunk: usize,
+) -> Result<(usize, usize, Option<String>), String> {
+    let start = chunk
+        .checked_mul(MAX_CHUNK_SIZE)
+        .filter(|start| *start < body_len)
+        .ok_or_else(|| format!("Streaming token {chunk} out of range"))?;
+    let end = body_len.min(start + MAX_CHUNK_SIZE);
+    let next_token = (end < body_len).then(|| (chunk + 1).to_string());
+
+    Ok((start, end, next_token))
+}
+
+/// Fetches the remaining chunks of a streamed response, and returns the response with the
+/// full body, which can then be verified against its certificate.
+///
+/// `call_callback` sends a bHTTP request to [STREAMING_CALLBACK_METHOD] of the canister and
+/// returns the bHTTP response, e.g. with an agent query call. A chunk that can't be decoded
+/// fails with a [DecodeError].
+pub async fn reassemble_response<'a, F, Fut, E>(
+    req: &HttpRequest<'_>,
+    first: HttpResponse<'a>,
+    mut call_callback: F,
+) -> Result<HttpResponse<'a>, E>
+where
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<Vec<u8>, E>>,
+    E: From<DecodeError>,
+{
+    let mut token = streaming_token(first.headers());
+    if token.is_none() {
+        return Ok(first);
+    }
+
+    let mut body = first.body().to_vec();
+    while let Some(next_token) = token {
+        let mut headers = without_streaming_token(req.headers());
+        headers.push((STREAMING_TOKEN_HEADER_NAME.to_string(), next_token));
+        let chunk_req = HttpRequest::builder()
+            .with_method(req.method().clone())
+            .with_url(req.url().to_string())
+            .with_headers(headers)
+            .with_body(req.body().to_vec())
+            .build();
+
+        let chunk = decode_result(call_callback(encode_request(&chunk_req)).await?)?;
+        body.extend_from_slice(chunk.body());
+        token = streaming_token(chunk.headers());
+    }
+
+    Ok(HttpResponse::builder()
+        .with_status_code(first.status_code())
+        .with_headers(without_streaming_token(first.headers()))
+        .with_body(body)
+        .with_upgrade(first.upgrade().unwrap_or(false))
+        .build())
+}
+
+fn streaming_token(headers: &[HeaderField]) -> Option<String> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(STREAMING_TOKEN_HEADER_NAME))
+        .map(|(_, value)| value.clone())
+}
+
+fn without_streaming_token(headers: &[HeaderField]) -> Vec<HeaderField> {
+    headers
+        .iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case(STREAMING_TOKEN_HEADER_NAME))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use ic_http_certification::{Method, StatusCode};
+
+    use super::*;
+    use crate::{decode_args, encode_result};
+
+    /// Polls a future whose callbacks are all ready.
+    fn now_or_never<T>(future: impl Future<Output = T>) -> T {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("The future is pending"),
+        }
+    }
+
+    /// The query handler of a canister, with a body of three chunks.
+    fn handler(_req: HttpRequest) -> HttpResponse<'static> {
+        HttpResponse::builder()
+            .with_status_code(StatusCode::OK)
+            .with_headers(vec![(
+                "content-type".to_string(),
+                "application/octet-stream".to_string(),
+            )])
+            .with_body(body())
+            .build()
+    }
+
+    fn body() -> Vec<u8> {
+        (0..2 * MAX_CHUNK_SIZE + 10).map(|i| i as u8).collect()
+    }
+
+    /// A query call to `http_request_v2` or to the streaming callback.
+    fn call(bytes: Vec<u8>) -> Vec<u8> {
+        encode_result(handler(decode_args(bytes)))
+    }
+
+    #[test]
+    fn streamed_response_is_reassembled() {
+        let req = HttpRequest::builder()
+            .with_method(Method::GET)
+            .with_url("/large")
+            .with_headers(vec![(
+                ACCEPT_STREAMING_HEADER_NAME.to_string(),
+                "true".to_string(),
+            )])
+            .build();
+
+        let first = decode_result(call(encode_request(&req))).unwrap();
+        assert_eq!(first.body().len(), MAX_CHUNK_SIZE);
+        assert_eq!(streaming_token(first.headers()), Some("1".to_string()));
+
+        let res = now_or_never(reassemble_response(&req, first, |bytes| async {
+            Ok::<_, DecodeError>(call(bytes))
+        }))
+        .unwrap();
+
+        assert_eq!(res.status_code(), StatusCode::OK);
+        assert_eq!(
+            res.headers(),
+            [(
+                "content-type".to_string(),
+                "application/octet-stream".to_string()
+            )]
+        );
+        assert_eq!(res.body(), body());
+    }
+
+    #[test]
+    fn response_is_not_streamed_without_the_accept_header() {
+        let req = HttpRequest::builder()
+            .with_method(Method::GET)
+            .with_url("/large")
+            .build();
+
+        let res = decode_result(call(encode_request(&req))).unwrap();
+        assert_eq!(res.body(), body());
+        assert_eq!(streaming_token(res.headers()), None);
+    }
+
+    #[test]
+    fn body_of_exactly_one_chunk_has_no_next_token() {
+        assert_eq!(
+            chunk_range(MAX_CHUNK_SIZE, 0),
+            Ok((0, MAX_CHUNK_SIZE, None))
+        );
+        assert!(chunk_range(MAX_CHUNK_SIZE, 1).is_err());
+    }
+
+    #[test]
+    fn body_one_byte_over_has_a_second_chunk() {
+        let body_len = MAX_CHUNK_SIZE + 1;
+
+        assert_eq!(
+            chunk_range(body_len, 0),
+            Ok((0, MAX_CHUNK_SIZE, Some("1".to_string())))
+        );
+        assert_eq!(
+            chunk_range(body_len, 1),
+            Ok((MAX_CHUNK_SIZE, body_len, None))
+        );
+    }
+
+    #[test]
+    fn last_chunk_ends_with_the_body() {
+        let body_len = 3 * MAX_CHUNK_SIZE - 10;
+
+        assert_eq!(
+            chunk_range(body_len, 1),
+            Ok((MAX_CHUNK_SIZE, 2 * MAX_CHUNK_SIZE, Some("2".to_string())))
+        );
+        assert_eq!(
+            chunk_range(body_len, 2),
+            Ok((2 * MAX_CHUNK_SIZE, body_len, None))
+        );
+        assert!(chunk_range(body_len, 3).is_err());
+        assert!(chunk_range(body_len, usize::MAX).is_err());
+    }
+}
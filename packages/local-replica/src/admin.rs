@@ -0,0 +1,406 @@
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use candid::Principal;
+
+use axum::{
+    Json, Router,
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+};
+use ic_bn_lib_common::traits::Healthy;
+use ic_gateway::ic_bn_lib::{
+    ic_agent::Agent,
+    reqwest::{self, Url},
+    utils::health_manager::HealthManager,
+};
+use pocket_ic::nonblocking::PocketIc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+
+use crate::{
+    deploy::{
+        CanisterDeployment, DeployedCanister, ReplicaError, canister_urls, deploy_canister,
+        read_canister_ids, write_canister_id,
+    },
+    gateway::{ReplicaUrl, RootKey},
+    http_protocol::HttpProtocolRouter,
+};
+
+pub const CANISTERS_PATH: &str = "/_replica/canisters";
+pub const HEALTH_PATH: &str = "/_replica/health";
+pub const READY_PATH: &str = "/_replica/ready";
+
+/// The most canisters probed in each canister range of the PocketIC topology.
+const MAX_PROBED_CANISTERS: usize = 1000;
+
+/// State shared by the admin endpoints served by the gateway under `/_replica`.
+#[derive(Clone)]
+pub struct AdminState {
+    /// The PocketIC instance started by the replica, if it wasn't given a replica URL.
+    pub pic: Option<Arc<PocketIc>>,
+    pub replica_url: ReplicaUrl,
+    pub gateway_url: Url,
+    /// The domains served by the gateway, for the URLs of the deployed canisters.
+    pub domains: Vec<String>,
+    pub canister_ids_file: PathBuf,
+    /// The agent checking the replica, reused by every readiness check.
+    agent: Agent,
+    /// The client checking the status endpoint of the PocketIC server.
+    http_client: reqwest::Client,
+    /// Set once the root key has been fetched by the agent.
+    root_key_fetched: Arc<OnceCell<()>>,
+    /// The health of the background tasks of ic-gateway, set once the gateway has started.
+    gateway_health: Arc<OnceLock<Arc<HealthManager>>>,
+    /// Forgets the detected HTTP protocol of the deployed canisters, set once the gateway
+    /// has started.
+    http_protocol_router: Arc<OnceLock<Arc<HttpProtocolRouter>>>,
+    /// Whether the canisters of the config file have been deployed.
+    started: Arc<AtomicBool>,
+}
+
+/// The response of [READY_PATH].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub replica: ReadinessCheck,
+    pub root_key: ReadinessCheck,
+    pub gateway: ReadinessCheck,
+    pub startup: ReadinessCheck,
+    pub canisters: Vec<InstalledCanister>,
+}
+
+/// A canister with a module installed in the replica, whether it was deployed by the replica,
+/// by dfx or restored from the state directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledCanister {
+    /// The name of the canister in the canister ids file, if it's listed there.
+    pub name: Option<String>,
+    pub canister_id: Principal,
+    /// The hex encoded SHA-256 of the installed module.
+    pub module_hash: String,
+    pub urls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadinessCheck {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl ReadinessCheck {
+    fn new(result: Result<String, String>) -> Self {
+        match result {
+            Ok(detail) => Self { ok: true, detail },
+            Err(detail) => Self { ok: false, detail },
+        }
+    }
+}
+
+impl AdminState {
+    pub fn new(
+        pic: Option<Arc<PocketIc>>,
+        replica_url: ReplicaUrl,
+        gateway_url: Url,
+        domains: Vec<String>,
+        canister_ids_file: PathBuf,
+    ) -> Result<Self, anyhow::Error> {
+        let agent = Agent::builder()
+            .with_url(replica_url.into_url().as_str())
+            .build()
+            .context("Failed to create an agent")?;
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .context("Failed to create an HTTP client")?;
+
+        Ok(Self {
+            pic,
+            replica_url,
+            gateway_url,
+            domains,
+            canister_ids_file,
+            agent,
+            http_client,
+            root_key_fetched: Arc::default(),
+            gateway_health: Arc::default(),
+            http_protocol_router: Arc::default(),
+            started: Arc::default(),
+        })
+    }
+
+    /// Deploys the canister and records its id in the canister ids file.
+    pub async fn deploy(
+        &self,
+        deployment: &CanisterDeployment,
+    ) -> Result<DeployedCanister, anyhow::Error> {
+        let pic = self
+            .pic
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Canisters can only be deployed to PocketIC"))?;
+
+        let deployed = deploy_canister(pic, deployment, &self.gateway_url, &self.domains).await?;
+        write_canister_id(
+            &self.canister_ids_file,
+            &deployed.name,
+            deployed.canister_id,
+        )?;
+
+        if let Some(router) = self.http_protocol_router.get() {
+            router.forget_detected(deployed.canister_id);
+        }
+
+        Ok(deployed)
+    }
+
+    /// Marks the replica as ready once the canisters of the config file are deployed.
+    pub fn set_started(&self) {
+        self.started.store(true, Ordering::SeqCst);
+    }
+
+    /// Makes the readiness depend on the health of ic-gateway, see [Gateway::health_manager].
+    ///
+    /// [Gateway::health_manager]: crate::gateway::Gateway::health_manager
+    pub fn set_gateway_health(&self, health_manager: Arc<HealthManager>) {
+        let _ = self.gateway_health.set(health_manager);
+    }
+
+    /// Makes the deployments reset the detected HTTP protocol of the canister, see
+    /// [Gateway::http_protocol_router].
+    ///
+    /// [Gateway::http_protocol_router]: crate::gateway::Gateway::http_protocol_router
+    pub fn set_http_protocol_router(&self, router: Arc<HttpProtocolRouter>) {
+        let _ = self.http_protocol_router.set(router);
+    }
+
+    /// Checks the status endpoint of the PocketIC server, or the status of the replica if it
+    /// was given a replica URL.
+    pub async fn replica_health(&self) -> Result<String, String> {
+        let Some(pic) = &self.pic else {
+            let url = self.replica_url.into_url();
+            return self
+                .agent
+                .status()
+                .await
+                .map(|_| format!("{url} is reachable"))
+                .map_err(|e| format!("{url} is not reachable: {e}"));
+        };
+
+        let url = pic
+            .get_server_url()
+            .join("status")
+            .map_err(|e| format!("Invalid PocketIC server URL: {e}"))?;
+        let response = self
+            .http_client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| format!("PocketIC is not reachable at {url}: {e}"))?;
+        if !response.status().is_success() {
+            return Err(format!("PocketIC returned {} at {url}", response.status()));
+        }
+
+        Ok(format!("PocketIC is reachable at {url}"))
+    }
+
+    /// The root key doesn't change, so it's only fetched until it succeeds once.
+    async fn fetch_root_key(&self) -> Result<(), String> {
+        self.root_key_fetched
+            .get_or_try_init(|| self.agent.fetch_root_key())
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Failed to fetch the root key: {e}"))
+    }
+
+    /// Lists the canisters with a module in PocketIC, including the ones installed by dfx or
+    /// restored from the state directory.
+    ///
+    /// PocketIC has no endpoint listing its canisters, so the ids are probed from the start of
+    /// the canister ranges of each subnet, where canisters are created in sequence, until the
+    /// first id without a canister. The ids of the canister ids file are probed as well, since
+    /// they may have been created with a specific id.
+    pub async fn installed_canisters(&self) -> Result<Vec<InstalledCanister>, String> {
+        let Some(pic) = &self.pic else {
+            return Ok(Vec::new());
+        };
+
+        let names = read_canister_ids(&self.canister_ids_file).map_err(|e| format!("{e:#}"))?;
+        let mut candidates = names.values().copied().collect::<BTreeSet<_>>();
+        for subnet in pic.topology().await.subnet_configs.values() {
+            for range in &subnet.canister_ranges {
+                let mut canister_id = Principal::from_slice(&range.start.canister_id);
+                for _ in 0..MAX_PROBED_CANISTERS {
+                    if !pic.canister_exists(canister_id).await {
+                        break;
+                    }
+                    candidates.insert(canister_id);
+
+                    match next_canister_id(canister_id) {
+                        Some(next) if next.as_slice() <= range.end.canister_id.as_slice() => {
+                            canister_id = next
+                        }
+                        _ => break,
+                    }
+                }
+            }
+        }
+
+        self.fetch_root_key().await?;
+        let mut canisters = Vec::new();
+        for canister_id in candidates {
+            // Canisters without a module have no module hash in their state tree
+            let Ok(module_hash) = self
+                .agent
+                .read_state_canister_info(canister_id, "module_hash")
+                .await
+            else {
+                continue;
+            };
+
+            canisters.push(InstalledCanister {
+                name: names
+                    .iter()
+                    .find(|(_, id)| **id == canister_id)
+                    .map(|(name, _)| name.clone()),
+                canister_id,
+                module_hash: hex::encode(module_hash),
+                urls: canister_urls(canister_id, &self.gateway_url, &self.domains),
+            });
+        }
+
+        Ok(canisters)
+    }
+
+    pub async fn readiness(&self) -> Readiness {
+        let replica = self.replica_health().await;
+
+        let root_key = match &self.replica_url.root_key {
+            RootKey::Fetch => self
+                .fetch_root_key()
+                .await
+                .map(|_| "The root key can be fetched".to_string()),
+            RootKey::File(path) => std::fs::metadata(path)
+                .map(|_| format!("The root key is read from {}", path.display()))
+                .map_err(|e| format!("Failed to read the root key {}: {e}", path.display())),
+            RootKey::Mainnet => Ok("The root key of mainnet is used".to_string()),
+        };
+
+        let gateway = match self.gateway_health.get() {
+            Some(health_manager) if health_manager.healthy() => {
+                Ok("The background tasks of ic-gateway are healthy".to_string())
+            }
+            Some(_) => Err("The background tasks of ic-gateway are not healthy".to_string()),
+            None => Err("The gateway is starting".to_string()),
+        };
+
+        let startup = if self.started.load(Ordering::SeqCst) {
+            Ok("The canisters of the config file are deployed".to_string())
+        } else {
+            Err("The canisters of the config file are being deployed".to_string())
+        };
+
+        let replica = ReadinessCheck::new(replica);
+        let root_key = ReadinessCheck::new(root_key);
+        let gateway = ReadinessCheck::new(gateway);
+        let startup = ReadinessCheck::new(startup);
+
+        // The PocketIC client panics when the server doesn't answer, so the canisters are only
+        // listed while it's reachable
+        let canisters = if replica.ok {
+            self.installed_canisters().await.unwrap_or_else(|e| {
+                tracing::warn!("Failed to list the installed canisters: {e}");
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+
+        Readiness {
+            ready: replica.ok && root_key.ok && gateway.ok && startup.ok,
+            replica,
+            root_key,
+            gateway,
+            startup,
+            canisters,
+        }
+    }
+}
+
+pub fn admin_router(state: AdminState) -> Router {
+    Router::new()
+        .route(CANISTERS_PATH, post(deploy_canister_handler))
+        .route(HEALTH_PATH, get(health_handler))
+        .route(READY_PATH, get(ready_handler))
+        .with_state(state)
+}
+
+async fn deploy_canister_handler(
+    State(state): State<AdminState>,
+    Json(deployment): Json<CanisterDeployment>,
+) -> Result<Json<DeployedCanister>, (StatusCode, String)> {
+    state.deploy(&deployment).await.map(Json).map_err(|e| {
+        // A failure of PocketIC to create or install the canister is not the fault of the request
+        let status = if e.is::<ReplicaError>() {
+            StatusCode::BAD_GATEWAY
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        (status, format!("{e:#}"))
+    })
+}
+
+/// The gateway is serving requests and the replica behind it is reachable.
+async fn health_handler(State(state): State<AdminState>) -> (StatusCode, Json<serde_json::Value>) {
+    match state.replica_health().await {
+        Ok(_) => (StatusCode::OK, Json(serde_json::json!({ "status": "ok" }))),
+        Err(detail) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "status": "unavailable", "detail": detail })),
+        ),
+    }
+}
+
+async fn ready_handler(State(state): State<AdminState>) -> (StatusCode, Json<Readiness>) {
+    let readiness = state.readiness().await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(readiness))
+}
+
+/// The id after the canister id in its range: the first 8 bytes of a canister id are its
+/// big-endian index, followed by the `0x01 0x01` suffix of opaque ids.
+fn next_canister_id(canister_id: Principal) -> Option<Principal> {
+    let (index, suffix) = canister_id.as_slice().split_first_chunk::<8>()?;
+    let next = u64::from_be_bytes(*index).checked_add(1)?;
+    Some(Principal::from_slice(
+        &[&next.to_be_bytes()[..], suffix].concat(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_canister_id_increments_the_index() {
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+
+        assert_eq!(
+            next_canister_id(canister_id),
+            Some(Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap())
+        );
+    }
+}
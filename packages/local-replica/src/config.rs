@@ -0,0 +1,89 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use candid::Principal;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    deploy::CanisterDeployment, fault::FaultRule, gateway::Routing, http_protocol::HttpProtocol,
+    logging::LogFormat, pocket_ic::IcpFeature, watch::WatchTarget,
+};
+
+/// Settings that can be read from a TOML config file.
+///
+/// Every setting can also be passed on the command line, which takes precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// A replica URL, or a list of them.
+    #[serde(deserialize_with = "one_or_many")]
+    pub replica_url: Vec<String>,
+    pub root_key: Option<PathBuf>,
+    pub fetch_root_key: bool,
+    pub routing: Option<Routing>,
+    pub listen: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub domains: Vec<String>,
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub gateway_args: Vec<String>,
+    pub record: Option<PathBuf>,
+    pub debug_signatures: bool,
+    pub tls: bool,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub shutdown_timeout: Option<u64>,
+    pub metrics_listen: Option<SocketAddr>,
+    /// The HTTP protocol of canisters, by canister id.
+    pub http_protocols: BTreeMap<Principal, HttpProtocol>,
+    pub detect_http_protocol: Option<bool>,
+    pub state_dir: Option<PathBuf>,
+    pub canister_ids_file: Option<PathBuf>,
+    pub watch: Vec<WatchTarget>,
+    pub pocket_ic: PocketIcSection,
+    /// The `[[canisters]]` to deploy on startup.
+    pub canisters: Vec<CanisterDeployment>,
+    /// The `[[faults]]` injected from startup.
+    pub faults: Vec<FaultRule>,
+}
+
+/// The `[pocket-ic]` section of the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PocketIcSection {
+    pub server_binary: Option<PathBuf>,
+    pub nns_subnet: bool,
+    pub system_subnets: Option<usize>,
+    pub fiduciary_subnet: bool,
+    pub application_subnets: Option<usize>,
+    pub enable_features: Vec<IcpFeature>,
+    pub disable_features: Vec<IcpFeature>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
@@ -0,0 +1,255 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{Json, Router, http::StatusCode, routing::post};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use candid::Principal;
+use p256::{
+    ecdsa::{Signature, SigningKey, VerifyingKey, signature::Signer},
+    pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding},
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+use crate::signature::request_id;
+
+pub const DELEGATIONS_PATH: &str = "/_replica/delegations";
+
+/// The expiration of Internet Identity delegations by default.
+pub const DEFAULT_DELEGATION_EXPIRATION: Duration = Duration::from_secs(30 * 60);
+
+const DEFAULT_USER: &str = "default";
+
+/// Prefix of the seed of the root key of each user.
+const USER_KEY_SEED_PREFIX: &[u8] = b"local-replica delegation issuer:";
+
+/// Domain separator of delegation signatures, from the IC interface specification.
+const DELEGATION_DOMAIN_SEPARATOR: &[u8] = b"\x1Aic-request-auth-delegation";
+
+/// What to delegate, in the body of `POST` requests on [DELEGATIONS_PATH].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DelegationRequest {
+    /// The same user always gets the same principal, like an Internet Identity anchor.
+    pub user: String,
+    /// Base64 encoded DER public key of the session. If not set, a P-256 session key is
+    /// generated and returned with the delegation chain.
+    pub session_public_key: Option<String>,
+    /// Seconds until the delegation expires.
+    pub expiration_secs: u64,
+    /// Canisters the delegation is restricted to [default: all canisters].
+    pub targets: Vec<Principal>,
+}
+
+impl Default for DelegationRequest {
+    fn default() -> Self {
+        Self {
+            user: DEFAULT_USER.to_string(),
+            session_public_key: None,
+            expiration_secs: DEFAULT_DELEGATION_EXPIRATION.as_secs(),
+            targets: vec![],
+        }
+    }
+}
+
+/// A delegation chain, in the shape of the `delegationChain` of the `signature-key` header.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IssuedDelegation {
+    /// The principal of the user, derived from the root public key of the chain.
+    pub principal: Principal,
+    /// The generated session key, if no session public key was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_key: Option<SessionKey>,
+    pub delegation_chain: DelegationChain,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionKey {
+    /// PKCS#8 PEM encoded private key.
+    pub private_key: String,
+    /// Base64 encoded DER public key.
+    pub pub_key: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DelegationChain {
+    /// Base64 encoded DER public key of the user.
+    pub pub_key: String,
+    pub delegations: Vec<SignedDelegation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SignedDelegation {
+    pub delegation: Delegation,
+    /// Base64 encoded signature of the delegation by the previous key of the chain.
+    pub sig: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delegation {
+    /// Base64 encoded DER public key the delegation is issued to.
+    pub pub_key: String,
+    /// Nanoseconds since the Unix epoch, as a decimal string.
+    pub expiration: String,
+    /// Base64 encoded canister ids.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub targets: Option<Vec<String>>,
+}
+
+/// The map signed by the delegating key, from the IC interface specification.
+#[derive(Serialize)]
+struct DelegationContent {
+    pubkey: ByteBuf,
+    expiration: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    targets: Option<Vec<Principal>>,
+}
+
+/// Issues a delegation from the root key of the user to the session key, like Internet
+/// Identity does, except that the root key is a P-256 key derived from the user name
+/// instead of a canister signature.
+pub fn issue_delegation(request: &DelegationRequest) -> Result<IssuedDelegation, anyhow::Error> {
+    let user_key = user_key(&request.user)?;
+    let user_public_key = public_key_der(user_key.verifying_key())?;
+
+    let (session_public_key, session_key) = match &request.session_public_key {
+        Some(public_key) => {
+            let public_key = BASE64
+                .decode(public_key)
+                .map_err(|_| anyhow::anyhow!("The session public key is not base64 encoded"))?;
+            (public_key, None)
+        }
+        None => {
+            let signing_key = SigningKey::random(&mut rand::rngs::OsRng);
+            let public_key = public_key_der(signing_key.verifying_key())?;
+            let private_key = signing_key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map_err(|e| anyhow::anyhow!("Failed to encode the session key: {e}"))?;
+            let session_key = SessionKey {
+                private_key: private_key.to_string(),
+                pub_key: BASE64.encode(&public_key),
+            };
+            (public_key, Some(session_key))
+        }
+    };
+
+    let expiration = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .checked_add(Duration::from_secs(request.expiration_secs))
+        .and_then(|expiration| u64::try_from(expiration.as_nanos()).ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "The expiration of {} seconds is too far in the future",
+                request.expiration_secs
+            )
+        })?;
+    let targets = (!request.targets.is_empty()).then(|| request.targets.clone());
+
+    let content = DelegationContent {
+        pubkey: ByteBuf::from(session_public_key.clone()),
+        expiration,
+        targets: targets.clone(),
+    };
+    let signable = [DELEGATION_DOMAIN_SEPARATOR, &request_id(&content)?.to_vec()].concat();
+    let signature: Signature = user_key.sign(&signable);
+
+    Ok(IssuedDelegation {
+        principal: Principal::self_authenticating(&user_public_key),
+        session_key,
+        delegation_chain: DelegationChain {
+            pub_key: BASE64.encode(&user_public_key),
+            delegations: vec![SignedDelegation {
+                delegation: Delegation {
+                    pub_key: BASE64.encode(&session_public_key),
+                    expiration: expiration.to_string(),
+                    targets: targets.map(|targets| {
+                        targets
+                            .iter()
+                            .map(|target| BASE64.encode(target.as_slice()))
+                            .collect()
+                    }),
+                },
+                sig: BASE64.encode(signature.to_bytes()),
+            }],
+        },
+    })
+}
+
+/// The root key of the user, which is the same on every run.
+fn user_key(user: &str) -> Result<SigningKey, anyhow::Error> {
+    let seed = Sha256::digest([USER_KEY_SEED_PREFIX, user.as_bytes()].concat());
+    SigningKey::from_slice(&seed).map_err(|e| anyhow::anyhow!("Failed to derive the user key: {e}"))
+}
+
+fn public_key_der(key: &VerifyingKey) -> Result<Vec<u8>, anyhow::Error> {
+    key.to_public_key_der()
+        .map(|der| der.as_bytes().to_vec())
+        .map_err(|e| anyhow::anyhow!("Failed to encode the public key: {e}"))
+}
+
+pub fn delegation_router() -> Router {
+    Router::new().route(DELEGATIONS_PATH, post(issue_delegation_handler))
+}
+
+async fn issue_delegation_handler(
+    Json(request): Json<DelegationRequest>,
+) -> Result<Json<IssuedDelegation>, (StatusCode, String)> {
+    issue_delegation(&request)
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::{ecdsa::signature::Verifier, pkcs8::DecodePublicKey};
+
+    use super::*;
+
+    #[test]
+    fn delegation_is_signed_by_the_root_key() {
+        let target = Principal::from_text("bkyz2-fmaaa-aaaaa-qaaaq-cai").unwrap();
+        let issued = issue_delegation(&DelegationRequest {
+            targets: vec![target],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let chain = &issued.delegation_chain;
+        let root_public_key = BASE64.decode(&chain.pub_key).unwrap();
+        assert_eq!(
+            issued.principal,
+            Principal::self_authenticating(&root_public_key)
+        );
+
+        let signed = &chain.delegations[0];
+        let content = DelegationContent {
+            pubkey: ByteBuf::from(BASE64.decode(&signed.delegation.pub_key).unwrap()),
+            expiration: signed.delegation.expiration.parse().unwrap(),
+            targets: Some(vec![target]),
+        };
+        let signable = [
+            DELEGATION_DOMAIN_SEPARATOR,
+            &request_id(&content).unwrap().to_vec(),
+        ]
+        .concat();
+        let signature = Signature::from_slice(&BASE64.decode(&signed.sig).unwrap()).unwrap();
+
+        VerifyingKey::from_public_key_der(&root_public_key)
+            .unwrap()
+            .verify(&signable, &signature)
+            .expect("The delegation signature doesn't verify");
+    }
+
+    #[test]
+    fn expiration_overflow_is_rejected() {
+        let result = issue_delegation(&DelegationRequest {
+            expiration_secs: u64::MAX,
+            ..Default::default()
+        });
+        assert!(result.is_err());
+    }
+}
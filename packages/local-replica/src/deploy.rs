@@ -0,0 +1,277 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_gateway::ic_bn_lib::reqwest::Url;
+use pocket_ic::{common::rest::RawEffectivePrincipal, nonblocking::PocketIc};
+use serde::{Deserialize, Serialize};
+
+/// Candid encoding of an empty argument list, `()`.
+const EMPTY_CANDID_ARGS: &[u8] = b"DIDL\x00\x00";
+const CANISTER_CYCLES: u128 = 2_000_000_000_000;
+const CANISTER_IDS_NETWORK: &str = "local";
+
+/// A wasm module to install in PocketIC, either from the config file or from `replica deploy`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CanisterDeployment {
+    /// Name of the canister in the canister ids file, defaults to the wasm file name.
+    pub name: Option<String>,
+    /// Path to the wasm module, optionally gzipped.
+    pub wasm: PathBuf,
+    /// Hex encoded Candid init or upgrade argument, defaults to `()`.
+    pub init_arg: Option<String>,
+    /// Install the module in this canister, creating it if it doesn't exist.
+    pub canister_id: Option<Principal>,
+}
+
+/// A call to PocketIC failed, as opposed to an invalid deployment.
+#[derive(Debug)]
+pub struct ReplicaError(String);
+
+impl fmt::Display for ReplicaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ReplicaError {}
+
+#[derive(CandidType)]
+struct CreateCanisterArgs {
+    amount: Option<Nat>,
+}
+
+#[derive(CandidType)]
+struct TopUpCanisterArgs {
+    canister_id: Principal,
+    amount: Nat,
+}
+
+#[derive(Deserialize)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeployedCanister {
+    pub name: String,
+    pub canister_id: Principal,
+    pub upgraded: bool,
+    pub urls: Vec<String>,
+}
+
+impl CanisterDeployment {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            let file_name = self
+                .wasm
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            file_name
+                .trim_end_matches(".gz")
+                .trim_end_matches(".wasm")
+                .to_string()
+        })
+    }
+
+    fn arg(&self) -> Result<Vec<u8>, anyhow::Error> {
+        match &self.init_arg {
+            Some(init_arg) => {
+                hex::decode(init_arg).context("The init argument must be hex encoded")
+            }
+            None => Ok(EMPTY_CANDID_ARGS.to_vec()),
+        }
+    }
+}
+
+/// Installs the wasm module in a new canister, or upgrades the canister if it already has a module.
+///
+/// Gzipped modules are decompressed by the management canister, so they are passed as is.
+/// The URLs of the canister are built from the gateway URL and the domains it serves.
+pub async fn deploy_canister(
+    pic: &PocketIc,
+    deployment: &CanisterDeployment,
+    gateway_url: &Url,
+    domains: &[String],
+) -> Result<DeployedCanister, anyhow::Error> {
+    let wasm_module = std::fs::read(&deployment.wasm)
+        .with_context(|| format!("Failed to read wasm module {}", deployment.wasm.display()))?;
+    let arg = deployment.arg()?;
+
+    let canister_id = match deployment.canister_id {
+        Some(canister_id) if pic.canister_exists(canister_id).await => canister_id,
+        Some(canister_id) => pic
+            .create_canister_with_id(None, None, canister_id)
+            .await
+            .map_err(|e| ReplicaError(format!("Failed to create canister {canister_id}: {e}")))?,
+        None => create_canister(pic).await?,
+    };
+
+    let has_module = pic
+        .canister_status(canister_id, None)
+        .await
+        .map_err(|e| {
+            ReplicaError(format!(
+                "Failed to get the status of canister {canister_id}: {e:?}"
+            ))
+        })?
+        .module_hash
+        .is_some();
+
+    if has_module {
+        pic.upgrade_canister(canister_id, wasm_module, arg, None)
+            .await
+            .map_err(|e| {
+                ReplicaError(format!("Failed to upgrade canister {canister_id}: {e:?}"))
+            })?;
+    } else {
+        call_management_canister(
+            pic,
+            RawEffectivePrincipal::CanisterId(canister_id.as_slice().to_vec()),
+            "provisional_top_up_canister",
+            Encode!(&TopUpCanisterArgs {
+                canister_id,
+                amount: Nat::from(CANISTER_CYCLES),
+            })?,
+        )
+        .await
+        .map_err(|e| {
+            ReplicaError(format!(
+                "Failed to add cycles to canister {canister_id}: {e}"
+            ))
+        })?;
+        // Unlike `install_canister`, which panics, this returns the error. Both install the
+        // module, since the canister has none.
+        pic.reinstall_canister(canister_id, wasm_module, arg, None)
+            .await
+            .map_err(|e| {
+                ReplicaError(format!("Failed to install canister {canister_id}: {e:?}"))
+            })?;
+    }
+
+    Ok(DeployedCanister {
+        name: deployment.name(),
+        canister_id,
+        upgraded: has_module,
+        urls: canister_urls(canister_id, gateway_url, domains),
+    })
+}
+
+/// Creates a canister on any subnet, returning the error of the management canister instead of
+/// panicking like [PocketIc::create_canister].
+async fn create_canister(pic: &PocketIc) -> Result<Principal, ReplicaError> {
+    let reply = call_management_canister(
+        pic,
+        RawEffectivePrincipal::None,
+        "provisional_create_canister_with_cycles",
+        Encode!(&CreateCanisterArgs { amount: None })
+            .map_err(|e| ReplicaError(format!("Failed to encode the arguments: {e}")))?,
+    )
+    .await
+    .and_then(|reply| Decode!(&reply, CanisterIdRecord).map_err(|e| e.to_string()))
+    .map_err(|e| ReplicaError(format!("Failed to create a canister: {e}")))?;
+
+    Ok(reply.canister_id)
+}
+
+async fn call_management_canister(
+    pic: &PocketIc,
+    effective_principal: RawEffectivePrincipal,
+    method: &str,
+    arg: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    pic.update_call_with_effective_principal(
+        Principal::management_canister(),
+        effective_principal,
+        Principal::anonymous(),
+        method,
+        arg,
+    )
+    .await
+    .map_err(|e| format!("{e:?}"))
+}
+
+/// The `<canister-id>.<domain>` URLs of the canister on the domains that aren't IP addresses,
+/// then its URL with the `canisterId` query parameter.
+pub fn canister_urls(canister_id: Principal, gateway_url: &Url, domains: &[String]) -> Vec<String> {
+    let scheme = gateway_url.scheme();
+    let port = gateway_url.port_or_known_default().unwrap_or_default();
+    domains
+        .iter()
+        .filter(|domain| domain.parse::<IpAddr>().is_err())
+        .map(|domain| format!("{scheme}://{canister_id}.{domain}:{port}"))
+        .chain([format!("{gateway_url}?canisterId={canister_id}")])
+        .collect()
+}
+
+/// The ids of the canisters on the local network in a `canister_ids.json` file, by name.
+pub fn read_canister_ids(path: &Path) -> Result<BTreeMap<String, Principal>, anyhow::Error> {
+    Ok(read_canister_ids_file(path)?
+        .into_iter()
+        .filter_map(|(name, networks)| {
+            let canister_id = Principal::from_text(networks.get(CANISTER_IDS_NETWORK)?).ok()?;
+            Some((name, canister_id))
+        })
+        .collect())
+}
+
+fn read_canister_ids_file(
+    path: &Path,
+) -> Result<BTreeMap<String, BTreeMap<String, String>>, anyhow::Error> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Adds the canister to a `canister_ids.json` file, in the same format as dfx.
+pub fn write_canister_id(
+    path: &Path,
+    name: &str,
+    canister_id: Principal,
+) -> Result<(), anyhow::Error> {
+    let mut canister_ids = read_canister_ids_file(path)?;
+    canister_ids
+        .entry(name.to_string())
+        .or_default()
+        .insert(CANISTER_IDS_NETWORK.to_string(), canister_id.to_text());
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let contents = serde_json::to_string_pretty(&canister_ids)?;
+    std::fs::write(path, contents + "\n")
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canister_urls_use_the_domains_that_are_not_ip_addresses() {
+        let canister_id = Principal::from_text("bkyz2-fmaaa-aaaaa-qaaaq-cai").unwrap();
+        let gateway_url = Url::parse("http://127.0.0.1:4943").unwrap();
+        let domains = ["my-app.localhost", "127.0.0.1"].map(str::to_string);
+
+        assert_eq!(
+            canister_urls(canister_id, &gateway_url, &domains),
+            [
+                "http://bkyz2-fmaaa-aaaaa-qaaaq-cai.my-app.localhost:4943",
+                "http://127.0.0.1:4943/?canisterId=bkyz2-fmaaa-aaaaa-qaaaq-cai",
+            ]
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use axum::Router;
+use candid::Principal;
+use ic_gateway::ic_bn_lib::reqwest::{self, Url};
+use pocket_ic::nonblocking::PocketIc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing_core::LevelFilter;
+
+use crate::{
+    deploy::{CanisterDeployment, DeployedCanister, canister_urls, deploy_canister},
+    fault::FaultInjector,
+    gateway::{DEFAULT_SHUTDOWN_TIMEOUT, GatewayConfig, ReplicaUrl, start_gateway},
+    logging::LogFormat,
+    pocket_ic::{PocketIcConfig, start_pocket_ic, stop_pocket_ic},
+    tls::TlsSource,
+};
+
+/// Port 0 lets the OS pick a free port, so that tests can run in parallel.
+const EPHEMERAL_LISTEN_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Builds an [Environment].
+pub struct EnvironmentBuilder {
+    pocket_ic_config: PocketIcConfig,
+    log_filter: String,
+    tls: Option<TlsSource>,
+    canisters: Vec<CanisterDeployment>,
+}
+
+impl EnvironmentBuilder {
+    pub fn with_pocket_ic_config(mut self, pocket_ic_config: PocketIcConfig) -> Self {
+        self.pocket_ic_config = pocket_ic_config;
+        self
+    }
+
+    /// Sets the log level of the gateway, [LevelFilter::WARN] by default.
+    pub fn with_log_level(self, log_level: LevelFilter) -> Self {
+        self.with_log_filter(log_level.to_string())
+    }
+
+    /// Sets the log filter of the gateway, in the `EnvFilter` syntax, e.g. `warn,ic_gateway=debug`.
+    pub fn with_log_filter(mut self, log_filter: impl Into<String>) -> Self {
+        self.log_filter = log_filter.into();
+        self
+    }
+
+    /// Serves the gateway over HTTPS. Clients have to trust the certificate, see [Environment::client].
+    pub fn with_tls(mut self, tls: TlsSource) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Installs the canister when the environment starts.
+    pub fn with_canister(mut self, deployment: CanisterDeployment) -> Self {
+        self.canisters.push(deployment);
+        self
+    }
+
+    /// Installs the wasm module in a new canister when the environment starts.
+    pub fn with_wasm(self, wasm: impl Into<PathBuf>) -> Self {
+        self.with_canister(CanisterDeployment {
+            name: None,
+            wasm: wasm.into(),
+            init_arg: None,
+            canister_id: None,
+        })
+    }
+
+    /// Starts PocketIC and the gateway, then installs the canisters.
+    pub async fn start(self) -> Result<Environment, anyhow::Error> {
+        let shutdown_token = CancellationToken::new();
+
+        let (pic, pic_url) = start_pocket_ic(&self.pocket_ic_config).await?;
+
+        let gateway_config = GatewayConfig {
+            listen_addr: EPHEMERAL_LISTEN_ADDR,
+            domains: vec![],
+            log_filter: self.log_filter,
+            log_format: LogFormat::Text,
+            extra_args: vec![],
+            record_file: None,
+            debug_signatures: false,
+            tls: self.tls,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            faults: vec![],
+            metrics_listen_addr: None,
+            http_protocols: BTreeMap::new(),
+            // The responses of legacy canisters aren't verified, so they are never detected
+            detect_http_protocol: false,
+        };
+        let gateway = start_gateway(
+            &gateway_config,
+            &ReplicaUrl::new_pocket_ic(pic_url.clone()),
+            Router::new(),
+            shutdown_token.clone(),
+        )
+        .await?;
+        let gateway_addr = gateway.local_addr()?;
+        let gateway_url = gateway.url().clone();
+        let domains = gateway_config.domains();
+        let client = gateway_config.client()?;
+        let fault_injector = gateway.fault_injector();
+
+        let server = tokio::spawn(gateway.serve(shutdown_token.clone().cancelled_owned()));
+
+        let mut env = Environment {
+            pic,
+            pic_url,
+            pocket_ic_config: self.pocket_ic_config,
+            gateway_addr,
+            gateway_url,
+            domains,
+            client,
+            fault_injector,
+            canisters: vec![],
+            shutdown_token,
+            server,
+        };
+
+        for deployment in &self.canisters {
+            let deployed = env.install_canister(deployment).await?;
+            env.canisters.push(deployed);
+        }
+
+        Ok(env)
+    }
+}
+
+/// A PocketIC instance and an HTTP gateway in front of it, listening on an ephemeral port.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), anyhow::Error> {
+/// let env = local_replica::Environment::builder()
+///     .with_wasm("target/wasm32-unknown-unknown/release/todo_app_backend.wasm")
+///     .start()
+///     .await?;
+///
+/// let url = env.canister_url(env.canisters()[0].canister_id);
+/// // Send HTTP requests to `url`...
+///
+/// env.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Environment {
+    pic: PocketIc,
+    pic_url: Url,
+    pocket_ic_config: PocketIcConfig,
+    gateway_addr: SocketAddr,
+    gateway_url: Url,
+    /// The domains served by the gateway, for the URLs of the canisters.
+    domains: Vec<String>,
+    client: reqwest::Client,
+    fault_injector: Arc<FaultInjector>,
+    canisters: Vec<DeployedCanister>,
+    shutdown_token: CancellationToken,
+    server: JoinHandle<std::io::Result<()>>,
+}
+
+impl Environment {
+    pub fn builder() -> EnvironmentBuilder {
+        EnvironmentBuilder {
+            pocket_ic_config: PocketIcConfig::default(),
+            log_filter: LevelFilter::WARN.to_string(),
+            tls: None,
+            canisters: vec![],
+        }
+    }
+
+    pub fn pic(&self) -> &PocketIc {
+        &self.pic
+    }
+
+    pub fn pocket_ic_url(&self) -> &Url {
+        &self.pic_url
+    }
+
+    pub fn gateway_addr(&self) -> SocketAddr {
+        self.gateway_addr
+    }
+
+    pub fn gateway_url(&self) -> Url {
+        self.gateway_url.clone()
+    }
+
+    /// An HTTP client for the gateway, which trusts the generated CA when TLS is enabled.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// The faults injected in the requests forwarded to PocketIC, which can be changed at any time.
+    pub fn faults(&self) -> &FaultInjector {
+        &self.fault_injector
+    }
+
+    /// The URL of the canister on the gateway, using a `<canister-id>.localhost` subdomain.
+    pub fn canister_url(&self, canister_id: Principal) -> Url {
+        let url = &canister_urls(canister_id, &self.gateway_url, &self.domains)[0];
+        Url::parse(url).expect("Invalid canister URL")
+    }
+
+    /// The canisters installed when the environment started, in the order they were added to the builder.
+    pub fn canisters(&self) -> &[DeployedCanister] {
+        &self.canisters
+    }
+
+    /// Installs the wasm module, or upgrades the canister if it already has a module.
+    pub async fn install_canister(
+        &self,
+        deployment: &CanisterDeployment,
+    ) -> Result<DeployedCanister, anyhow::Error> {
+        deploy_canister(&self.pic, deployment, &self.gateway_url, &self.domains).await
+    }
+
+    /// Stops the gateway, then PocketIC. PocketIC is stopped even if the gateway failed, whose
+    /// error is returned afterwards.
+    pub async fn shutdown(self) -> Result<(), anyhow::Error> {
+        self.shutdown_token.cancel();
+        let server_result = self.server.await;
+        stop_pocket_ic(self.pic, &self.pocket_ic_config).await;
+
+        server_result??;
+        Ok(())
+    }
+}
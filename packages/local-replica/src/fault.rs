@@ -0,0 +1,649 @@
+use std::{
+    io::Cursor,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use axum::{
+    BoxError, Json, Router,
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header::CONTENT_LENGTH},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use bhttp::{ControlData, Message, Mode};
+use ic_bn_lib_common::traits::http::Client;
+use ic_gateway::ic_bn_lib::reqwest;
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+
+pub const FAULTS_PATH: &str = "/_replica/faults";
+
+/// How old the expired certificates are, well beyond the ingress expiry of the agents.
+const CERTIFICATE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Where the faults of a rule are injected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FaultTarget {
+    /// The responses the gateway sends to its clients, after ic-gateway has decoded and
+    /// verified the response of the replica.
+    #[default]
+    Client,
+    /// The responses of the replica to the requests ic-gateway sends to serve the canisters
+    /// over HTTP, before ic-gateway verifies and decodes them.
+    Replica,
+}
+
+/// A fault injected in the responses the gateway sends to its clients, or in the responses
+/// of the replica to ic-gateway, see [FaultTarget].
+///
+/// The fields are applied in order: the delay, then the dropped connection or the error
+/// status, then the corrupted headers, the truncated body and the expired certificate of
+/// the response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FaultRule {
+    /// Requests whose path starts with this prefix are affected, e.g. `/api/v2/canister`.
+    pub path: String,
+    /// Where the faults are injected [default: client].
+    #[serde(default)]
+    pub target: FaultTarget,
+    /// Only requests with this method are affected [default: all methods].
+    #[serde(default)]
+    pub method: Option<String>,
+    /// Milliseconds to wait before handling the request.
+    #[serde(default)]
+    pub delay_ms: Option<u64>,
+    /// Respond with this status instead of forwarding the request.
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// Close the connection without sending a complete response.
+    #[serde(default)]
+    pub drop_connection: bool,
+    /// Response headers whose value is altered by one character, as received by the client.
+    #[serde(default)]
+    pub corrupt_headers: Vec<String>,
+    /// Only send the first bytes of the response body. For the replica, the body of the
+    /// bHTTP response returned by the canister is cut.
+    #[serde(default)]
+    pub truncate_body: Option<usize>,
+    /// Move the time of the certificates of the replica an hour back, only for the replica.
+    #[serde(default)]
+    pub expire_certificate: bool,
+}
+
+impl FaultRule {
+    /// Refuses the faults that can't be injected in the responses of [FaultRule::target].
+    pub fn validate(&self) -> Result<(), String> {
+        if self.expire_certificate && self.target != FaultTarget::Replica {
+            return Err(format!(
+                "expire-certificate only applies to the replica, set target = \"replica\" for {}",
+                self.path
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn matches(&self, target: FaultTarget, method: &Method, path: &str) -> bool {
+        self.target == target
+            && path.starts_with(&self.path)
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|rule_method| method.as_str().eq_ignore_ascii_case(rule_method))
+    }
+}
+
+/// The fault rules, which can be changed while the gateway is running.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    rules: RwLock<Vec<FaultRule>>,
+}
+
+impl FaultInjector {
+    pub fn new(rules: Vec<FaultRule>) -> Self {
+        Self {
+            rules: RwLock::new(rules),
+        }
+    }
+
+    pub fn rules(&self) -> Vec<FaultRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn set_rules(&self, rules: Vec<FaultRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    pub fn add_rule(&self, rule: FaultRule) {
+        self.rules.write().unwrap().push(rule);
+    }
+
+    /// The first rule of the target matching the request.
+    fn matching_rule(&self, target: FaultTarget, method: &Method, path: &str) -> Option<FaultRule> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .find(|rule| rule.matches(target, method, path))
+            .cloned()
+    }
+}
+
+/// Serves the rules on [FAULTS_PATH]. `PUT` replaces them, `POST` adds one and `DELETE` removes them all.
+pub fn fault_router(injector: Arc<FaultInjector>) -> Router {
+    Router::new()
+        .route(
+            FAULTS_PATH,
+            get(rules_handler)
+                .put(set_rules_handler)
+                .post(add_rule_handler)
+                .delete(clear_rules_handler),
+        )
+        .with_state(injector)
+}
+
+async fn rules_handler(State(injector): State<Arc<FaultInjector>>) -> Json<Vec<FaultRule>> {
+    Json(injector.rules())
+}
+
+async fn set_rules_handler(
+    State(injector): State<Arc<FaultInjector>>,
+    Json(rules): Json<Vec<FaultRule>>,
+) -> Result<Json<Vec<FaultRule>>, (StatusCode, String)> {
+    for rule in &rules {
+        rule.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    }
+    injector.set_rules(rules);
+    Ok(Json(injector.rules()))
+}
+
+async fn add_rule_handler(
+    State(injector): State<Arc<FaultInjector>>,
+    Json(rule): Json<FaultRule>,
+) -> Result<Json<Vec<FaultRule>>, (StatusCode, String)> {
+    rule.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    injector.add_rule(rule);
+    Ok(Json(injector.rules()))
+}
+
+async fn clear_rules_handler(State(injector): State<Arc<FaultInjector>>) -> StatusCode {
+    injector.set_rules(vec![]);
+    StatusCode::NO_CONTENT
+}
+
+/// Middleware that applies the first rule targeting the client that matches the request,
+/// if any.
+pub async fn inject_faults(
+    State(injector): State<Arc<FaultInjector>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(rule) =
+        injector.matching_rule(FaultTarget::Client, request.method(), request.uri().path())
+    else {
+        return next.run(request).await;
+    };
+
+    if let Some(delay_ms) = rule.delay_ms {
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+    }
+
+    if rule.drop_connection {
+        // The body fails before its first chunk, so the server closes the connection
+        return Response::new(Body::from_stream(failing_body(std::io::Error::other(
+            "Connection dropped by a fault rule",
+        ))));
+    }
+
+    if let Some(status) = rule.status {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        return (status, format!("Fault injected for {}", rule.path)).into_response();
+    }
+
+    let mut response = next.run(request).await;
+
+    corrupt_headers(response.headers_mut(), &rule.corrupt_headers);
+
+    let Some(truncate_body) = rule.truncate_body else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        // The client sees the failure of the body, as it would without the rule
+        Err(e) => return Response::from_parts(parts, Body::from_stream(failing_body(e))),
+    };
+    let body = body.slice(..truncate_body.min(body.len()));
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// The HTTP client ic-gateway sends its requests to the replica with, which applies the
+/// first rule targeting the replica that matches the request, if any.
+///
+/// The faults reach ic-gateway before it verifies the certificates and decodes the bHTTP
+/// responses of the canisters. The `/api/v2` and `/api/v3` requests of the clients are
+/// proxied by another client, so they are only affected by the rules targeting the client.
+#[derive(Debug)]
+pub struct FaultyClient {
+    inner: Arc<dyn Client>,
+    injector: Arc<FaultInjector>,
+}
+
+impl FaultyClient {
+    pub fn new(inner: Arc<dyn Client>, injector: Arc<FaultInjector>) -> Self {
+        Self { inner, injector }
+    }
+}
+
+#[async_trait]
+impl Client for FaultyClient {
+    async fn execute(&self, req: reqwest::Request) -> Result<reqwest::Response, reqwest::Error> {
+        let Some(rule) =
+            self.injector
+                .matching_rule(FaultTarget::Replica, req.method(), req.url().path())
+        else {
+            return self.inner.execute(req).await;
+        };
+
+        if let Some(delay_ms) = rule.delay_ms {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        }
+
+        if rule.drop_connection {
+            let body = failing_body(std::io::Error::other("Connection dropped by a fault rule"));
+            return Ok(reqwest::Response::from(axum::http::Response::new(
+                reqwest::Body::wrap_stream(body),
+            )));
+        }
+
+        if let Some(status) = rule.status {
+            let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            let mut response =
+                axum::http::Response::new(format!("Fault injected for {}", rule.path));
+            *response.status_mut() = status;
+            return Ok(reqwest::Response::from(response));
+        }
+
+        let response = self.inner.execute(req).await?;
+        let status = response.status();
+        let version = response.version();
+        let mut headers = response.headers().clone();
+        let mut body = response.bytes().await?;
+
+        corrupt_headers(&mut headers, &rule.corrupt_headers);
+        if rule.truncate_body.is_some() || rule.expire_certificate {
+            // The responses that aren't CBOR, such as errors in plain text, are left as is
+            if let Ok(altered) = alter_replica_response(&body, &rule) {
+                body = Bytes::from(altered);
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+            }
+        }
+
+        let mut altered = axum::http::Response::new(body);
+        *altered.status_mut() = status;
+        *altered.version_mut() = version;
+        *altered.headers_mut() = headers;
+        Ok(reqwest::Response::from(altered))
+    }
+}
+
+/// A body whose first chunk is the error.
+fn failing_body(
+    error: impl Into<BoxError>,
+) -> impl futures_util::Stream<Item = Result<Bytes, BoxError>> + Send + 'static {
+    let error = error.into();
+    futures_util::stream::once(async move { Err(error) })
+}
+
+fn corrupt_headers(headers: &mut HeaderMap, names: &[String]) {
+    for name in names {
+        if let Some(value) = headers.get_mut(name.as_str())
+            && let Ok(corrupted) = HeaderValue::from_bytes(&corrupt(value.as_bytes()))
+        {
+            *value = corrupted;
+        }
+    }
+}
+
+/// Applies the truncated body and the expired certificate of the rule to a CBOR response of
+/// the replica: the reply of a query, or the certificate of an update call or a `read_state`
+/// request.
+///
+/// ic-agent checks the age of the signatures of a query before checking the signatures, so
+/// an expired query fails with an outdated certificate. The time of a certificate is part
+/// of its signed tree, so an expired certificate fails its signature check instead, and so
+/// does a truncated reply when ic-gateway verifies the signatures of queries.
+fn alter_replica_response(body: &[u8], rule: &FaultRule) -> Result<Vec<u8>, serde_cbor::Error> {
+    let mut response: Value = serde_cbor::from_slice(body)?;
+    let Value::Map(fields) = &mut response else {
+        return Ok(body.to_vec());
+    };
+
+    if let Some(truncate_body) = rule.truncate_body
+        && let Some(Value::Map(reply)) = fields.get_mut(&text("reply"))
+        && let Some(Value::Bytes(arg)) = reply.get_mut(&text("arg"))
+    {
+        *arg = truncate_bhttp_body(arg, truncate_body);
+    }
+
+    if rule.expire_certificate
+        && let Some(Value::Array(signatures)) = fields.get_mut(&text("signatures"))
+    {
+        for signature in signatures {
+            if let Value::Map(signature) = signature
+                && let Some(Value::Integer(timestamp)) = signature.get_mut(&text("timestamp"))
+            {
+                *timestamp = expired_time().into();
+            }
+        }
+    }
+
+    if let Some(Value::Bytes(certificate)) = fields.get_mut(&text("certificate")) {
+        *certificate = alter_certificate(certificate, rule)?;
+    }
+
+    serde_cbor::to_vec(&response)
+}
+
+/// Rewrites the `time` and the `reply` leaves of the tree of a certificate.
+fn alter_certificate(certificate: &[u8], rule: &FaultRule) -> Result<Vec<u8>, serde_cbor::Error> {
+    let mut certificate: Value = serde_cbor::from_slice(certificate)?;
+    if let Value::Map(fields) = &mut certificate
+        && let Some(tree) = fields.get_mut(&text("tree"))
+    {
+        if let Some(truncate_body) = rule.truncate_body {
+            rewrite_leaves(tree, b"reply", &|reply| {
+                truncate_bhttp_body(reply, truncate_body)
+            });
+        }
+        if rule.expire_certificate {
+            rewrite_leaves(tree, b"time", &|_| leb128(expired_time()));
+        }
+    }
+
+    serde_cbor::to_vec(&certificate)
+}
+
+/// Rewrites the leaves labeled `label` of a hash tree, encoded as `[1, left, right]` for a
+/// fork, `[2, label, subtree]` for a labeled subtree and `[3, value]` for a leaf.
+fn rewrite_leaves(tree: &mut Value, label: &[u8], rewrite: &dyn Fn(&[u8]) -> Vec<u8>) {
+    let Value::Array(node) = tree else {
+        return;
+    };
+
+    match node.as_mut_slice() {
+        [Value::Integer(1), left, right] => {
+            rewrite_leaves(left, label, rewrite);
+            rewrite_leaves(right, label, rewrite);
+        }
+        [Value::Integer(2), Value::Bytes(node_label), subtree] => {
+            if node_label.as_slice() == label
+                && let Value::Array(leaf) = subtree
+                && let [Value::Integer(3), Value::Bytes(value)] = leaf.as_mut_slice()
+            {
+                *value = rewrite(value);
+            } else {
+                rewrite_leaves(subtree, label, rewrite);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Cuts the body of a bHTTP response and encodes it again, so that the response is still
+/// decoded. Replies that aren't bHTTP responses, such as Candid, are cut as is.
+fn truncate_bhttp_body(message: &[u8], len: usize) -> Vec<u8> {
+    let decoded = Message::read_bhttp(&mut Cursor::new(message));
+    let Ok(decoded) = decoded else {
+        return message[..len.min(message.len())].to_vec();
+    };
+    let ControlData::Response(status) = decoded.control() else {
+        return message[..len.min(message.len())].to_vec();
+    };
+
+    let mut truncated = Message::response(*status);
+    for field in decoded.header().iter() {
+        truncated.put_header(field.name(), field.value());
+    }
+    let content = decoded.content();
+    truncated.write_content(&content[..len.min(content.len())]);
+
+    let mut encoded = Vec::new();
+    match truncated.write_bhttp(Mode::KnownLength, &mut encoded) {
+        Ok(()) => encoded,
+        Err(_) => message[..len.min(message.len())].to_vec(),
+    }
+}
+
+/// The time of an expired certificate, in nanoseconds since the epoch.
+fn expired_time() -> u64 {
+    let time = SystemTime::now() - CERTIFICATE_AGE;
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            encoded.push(byte);
+            return encoded;
+        }
+        encoded.push(byte | 0x80);
+    }
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_string())
+}
+
+/// Changes a single alphanumeric character near the middle of the value, so it keeps
+/// its format.
+fn corrupt(value: &[u8]) -> Vec<u8> {
+    let mut value = value.to_vec();
+    let middle = value.len() / 2;
+    if let Some(i) = (middle..value.len())
+        .chain(0..middle)
+        .find(|&i| value[i].is_ascii_alphanumeric())
+    {
+        value[i] = if value[i] == b'A' { b'B' } else { b'A' };
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn rule(truncate_body: Option<usize>, expire_certificate: bool) -> FaultRule {
+        FaultRule {
+            path: "/api/v2/canister".to_string(),
+            target: FaultTarget::Replica,
+            method: None,
+            delay_ms: None,
+            status: None,
+            drop_connection: false,
+            corrupt_headers: vec![],
+            truncate_body,
+            expire_certificate,
+        }
+    }
+
+    fn bhttp_response(body: &[u8]) -> Vec<u8> {
+        let mut message = Message::response(bhttp::StatusCode::try_from(200_u16).unwrap());
+        message.put_header("content-type", "text/plain");
+        message.write_content(body);
+        let mut encoded = Vec::new();
+        message
+            .write_bhttp(Mode::KnownLength, &mut encoded)
+            .unwrap();
+        encoded
+    }
+
+    fn map(fields: impl IntoIterator<Item = (&'static str, Value)>) -> Value {
+        Value::Map(
+            fields
+                .into_iter()
+                .map(|(name, value)| (text(name), value))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    }
+
+    fn field<'a>(value: &'a Value, name: &str) -> &'a Value {
+        let Value::Map(fields) = value else {
+            panic!("expected a map, got {value:?}");
+        };
+        &fields[&text(name)]
+    }
+
+    #[test]
+    fn query_replies_are_truncated_and_expired() {
+        let query = map([
+            ("status", text("replied")),
+            (
+                "reply",
+                map([("arg", Value::Bytes(bhttp_response(b"Hello, world!")))]),
+            ),
+            (
+                "signatures",
+                Value::Array(vec![map([
+                    (
+                        "timestamp",
+                        Value::Integer(expired_time() as i128 + 3_600_000_000_000),
+                    ),
+                    ("identity", Value::Bytes(vec![1])),
+                ])]),
+            ),
+        ]);
+
+        let altered =
+            alter_replica_response(&serde_cbor::to_vec(&query).unwrap(), &rule(Some(5), true))
+                .unwrap();
+        let altered: Value = serde_cbor::from_slice(&altered).unwrap();
+
+        let Value::Bytes(arg) = field(field(&altered, "reply"), "arg") else {
+            panic!("expected the reply to be bytes");
+        };
+        let reply = Message::read_bhttp(&mut Cursor::new(arg.as_slice())).unwrap();
+        assert_eq!(reply.content(), b"Hello");
+        assert_eq!(
+            reply.header().get(b"content-type"),
+            Some(b"text/plain".as_slice())
+        );
+
+        let Value::Array(signatures) = field(&altered, "signatures") else {
+            panic!("expected the signatures to be an array");
+        };
+        let Value::Integer(timestamp) = field(&signatures[0], "timestamp") else {
+            panic!("expected the timestamp to be an integer");
+        };
+        assert!(*timestamp <= expired_time() as i128);
+    }
+
+    #[test]
+    fn certificates_get_an_old_time_and_a_truncated_reply() {
+        let labeled = |label: &str, subtree: Value| {
+            Value::Array(vec![
+                Value::Integer(2),
+                Value::Bytes(label.as_bytes().to_vec()),
+                subtree,
+            ])
+        };
+        let leaf = |value: Vec<u8>| Value::Array(vec![Value::Integer(3), Value::Bytes(value)]);
+        let tree = Value::Array(vec![
+            Value::Integer(1),
+            labeled(
+                "request_status",
+                labeled(
+                    "id",
+                    labeled("reply", leaf(bhttp_response(b"Hello, world!"))),
+                ),
+            ),
+            labeled("time", leaf(leb128(u64::MAX))),
+        ]);
+        let certificate = map([("tree", tree), ("signature", Value::Bytes(vec![0; 48]))]);
+        let call = map([
+            ("status", text("replied")),
+            (
+                "certificate",
+                Value::Bytes(serde_cbor::to_vec(&certificate).unwrap()),
+            ),
+        ]);
+
+        let altered =
+            alter_replica_response(&serde_cbor::to_vec(&call).unwrap(), &rule(Some(5), true))
+                .unwrap();
+        let altered: Value = serde_cbor::from_slice(&altered).unwrap();
+        let Value::Bytes(certificate) = field(&altered, "certificate") else {
+            panic!("expected the certificate to be bytes");
+        };
+        let certificate: Value = serde_cbor::from_slice(certificate).unwrap();
+        let Value::Array(tree) = field(&certificate, "tree") else {
+            panic!("expected the tree to be an array");
+        };
+
+        let mut replies = vec![];
+        let mut times = vec![];
+        collect_leaves(&tree[1], &mut replies, &mut times);
+        collect_leaves(&tree[2], &mut replies, &mut times);
+
+        let reply = Message::read_bhttp(&mut Cursor::new(replies[0].as_slice())).unwrap();
+        assert_eq!(reply.content(), b"Hello");
+        let time = times[0]
+            .iter()
+            .rev()
+            .fold(0, |time, byte| (time << 7) | u64::from(byte & 0x7f));
+        assert!(time <= expired_time());
+    }
+
+    fn collect_leaves(tree: &Value, replies: &mut Vec<Vec<u8>>, times: &mut Vec<Vec<u8>>) {
+        let Value::Array(node) = tree else {
+            return;
+        };
+        match node.as_slice() {
+            [Value::Integer(2), Value::Bytes(label), subtree] => {
+                if let Value::Array(leaf) = subtree
+                    && let [Value::Integer(3), Value::Bytes(value)] = leaf.as_slice()
+                {
+                    match label.as_slice() {
+                        b"reply" => replies.push(value.clone()),
+                        b"time" => times.push(value.clone()),
+                        _ => {}
+                    }
+                } else {
+                    collect_leaves(subtree, replies, times);
+                }
+            }
+            [Value::Integer(1), left, right] => {
+                collect_leaves(left, replies, times);
+                collect_leaves(right, replies, times);
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn expired_certificates_only_apply_to_the_replica() {
+        let mut client_rule = rule(None, true);
+        client_rule.target = FaultTarget::Client;
+
+        assert!(client_rule.validate().is_err());
+        assert!(rule(None, true).validate().is_ok());
+    }
+}
@@ -1,94 +1,463 @@
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap, future::Future, net::SocketAddr, path::PathBuf, sync::Arc,
+    time::Duration,
+};
 
-use axum::{Router, body::Bytes, extract::Request};
-use clap::Parser;
-use http_body_util::Full;
+use anyhow::Context;
+use axum::{
+    Router,
+    body::{Body, Bytes},
+    extract::Request,
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::IntoMakeService,
+};
+use axum_server::{Handle, tls_rustls::RustlsConfig};
+use candid::Principal;
+use clap::{Parser, ValueEnum};
+use http_body_util::{Full, LengthLimitError};
 use ic_bn_lib_common::types::http::ConnInfo;
 use ic_gateway::{
     Cli,
     ic_bn_lib::{
         http::{HyperClientLeastLoaded, ReqwestClient, dns::Resolver},
         ic_agent::agent::route_provider::RoundRobinRouteProvider,
-        prometheus::Registry,
-        reqwest::Url,
+        prometheus::{IntCounter, Registry},
+        reqwest::{self, Certificate, Url},
         tasks::TaskManager,
         utils::health_manager::HealthManager,
     },
     setup_router,
 };
+use serde::Deserialize;
 use tokio_util::sync::CancellationToken;
 use tower::ServiceExt;
 use tracing_core::LevelFilter;
-use tracing_subscriber::{
-    Registry as TracingRegistry, layer::SubscriberExt, reload, util::SubscriberInitExt,
+use tracing_subscriber::{Registry as TracingRegistry, reload};
+
+use crate::{
+    admin::CANISTERS_PATH,
+    delegation::delegation_router,
+    fault::{FAULTS_PATH, FaultInjector, FaultRule, FaultyClient, fault_router, inject_faults},
+    http_protocol::{HttpProtocol, HttpProtocolRouter, route_http_protocol},
+    logging::{LOG_FILTER_PATH, LogFormat, init_logging, log_filter_router},
+    metrics::{GatewayMetrics, UpgradeCountingClient, count_requests, serve_metrics},
+    record::{Recorder, record_traffic},
+    signature_debug::{
+        LAST_REQUESTS_PATH, SignatureDebugger, debug_signatures, signature_debug_router,
+    },
+    tls::TlsSource,
 };
 
-pub enum ReplicaUrl {
-    Mainnet(Url),
-    PocketIc(Url),
+/// How the gateway gets the root key, to verify the certificates of the replica.
+#[derive(Debug, Clone)]
+pub enum RootKey {
+    /// The root key of mainnet, built into ic-gateway.
+    Mainnet,
+    /// Fetched from the replica on startup, which is only safe with a local or test replica.
+    Fetch,
+    /// A DER encoded root key read from this file, e.g. the one of a shared testnet.
+    File(PathBuf),
+}
+
+/// How the gateway picks the replica nodes it sends requests to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Routing {
+    /// Round robin over the replica URLs.
+    Static,
+    /// The replica URLs are used as seeds to discover the API boundary nodes.
+    Discovery,
+}
+
+/// The replica the gateway forwards requests to.
+#[derive(Debug, Clone)]
+pub struct ReplicaUrl {
+    /// Never empty.
+    urls: Vec<Url>,
+    pub root_key: RootKey,
+    pub routing: Routing,
 }
 
 impl ReplicaUrl {
+    /// Mainnet, or another network using the mainnet root key, with discovery.
     pub fn new_remote(url: Url) -> Self {
-        ReplicaUrl::Mainnet(url)
+        Self {
+            urls: vec![url],
+            root_key: RootKey::Mainnet,
+            routing: Routing::Discovery,
+        }
     }
 
     pub fn new_pocket_ic(url: Url) -> Self {
-        ReplicaUrl::PocketIc(url)
+        Self {
+            urls: vec![url],
+            root_key: RootKey::Fetch,
+            routing: Routing::Static,
+        }
     }
 
+    pub fn new(urls: Vec<Url>, root_key: RootKey, routing: Routing) -> Result<Self, anyhow::Error> {
+        if urls.is_empty() {
+            anyhow::bail!("At least one replica URL is required");
+        }
+
+        Ok(Self {
+            urls,
+            root_key,
+            routing,
+        })
+    }
+
+    /// The first replica URL.
     pub fn into_url(&self) -> &Url {
-        match self {
-            ReplicaUrl::Mainnet(url) => url,
-            ReplicaUrl::PocketIc(url) => url,
+        &self.urls[0]
+    }
+
+    pub fn urls(&self) -> &[Url] {
+        &self.urls
+    }
+}
+
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The admin endpoints that deploy canisters, inject faults or change the log filter, and the
+/// one serving the signatures of the last requests, which can be replayed until they expire.
+const PRIVILEGED_PATHS: [&str; 4] = [
+    CANISTERS_PATH,
+    FAULTS_PATH,
+    LOG_FILTER_PATH,
+    LAST_REQUESTS_PATH,
+];
+
+/// The size limit of ingress messages, above which the replica rejects a request anyway.
+pub const MAX_REQUEST_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Buffers a request body up to [MAX_REQUEST_BODY_SIZE], or returns the error response:
+/// `413` above the limit, `400` if the body fails.
+pub async fn read_request_body(body: Body) -> Result<Bytes, Response> {
+    axum::body::to_bytes(body, MAX_REQUEST_BODY_SIZE)
+        .await
+        .map_err(|e| {
+            let e = e.into_inner();
+            if e.is::<LengthLimitError>() {
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("The request body is larger than {MAX_REQUEST_BODY_SIZE} bytes"),
+                )
+                    .into_response()
+            } else {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to read request body: {e}"),
+                )
+                    .into_response()
+            }
+        })
+}
+
+pub struct GatewayConfig {
+    pub listen_addr: SocketAddr,
+    /// Domains served by the gateway. If empty, `localhost` and the listen IP address are used.
+    pub domains: Vec<String>,
+    /// Log filter in the `EnvFilter` syntax, e.g. `info,ic_gateway=debug`.
+    pub log_filter: String,
+    pub log_format: LogFormat,
+    /// Additional flags passed to ic-gateway as is.
+    pub extra_args: Vec<String>,
+    /// If set, the requests and responses are recorded to this JSONL or HAR file.
+    pub record_file: Option<PathBuf>,
+    /// If set, a report on the signature of every signed request is logged and
+    /// served on `/_debug/last-requests`.
+    pub debug_signatures: bool,
+    /// If set, the gateway serves HTTPS instead of HTTP.
+    pub tls: Option<TlsSource>,
+    /// How long in-flight requests are waited for on shutdown.
+    pub shutdown_timeout: Duration,
+    /// The initial fault rules, which can be changed on `/_replica/faults`.
+    pub faults: Vec<FaultRule>,
+    /// If set, the Prometheus metrics are served on `/metrics` at this address.
+    pub metrics_listen_addr: Option<SocketAddr>,
+    /// The HTTP protocol of these canisters, instead of the detected one.
+    pub http_protocols: BTreeMap<Principal, HttpProtocol>,
+    /// If set, whether a canister uses the legacy HTTP interface is detected on its first request,
+    /// otherwise the canisters not in `http_protocols` use the v2 one.
+    pub detect_http_protocol: bool,
+}
+
+impl GatewayConfig {
+    /// The domains served by the gateway, with the defaults if none are configured.
+    pub fn domains(&self) -> Vec<String> {
+        if self.domains.is_empty() {
+            vec!["localhost".to_string(), self.listen_addr.ip().to_string()]
+        } else {
+            self.domains.clone()
+        }
+    }
+
+    /// The URL of the gateway listening on `addr`, which differs from the listen address
+    /// when it uses port 0.
+    pub fn url(&self, addr: SocketAddr) -> Url {
+        Url::parse(&format!("{}://{addr}", self.scheme())).expect("Invalid gateway URL")
+    }
+
+    /// The scheme of the requests to the gateway. Without TLS, it's the one `ic-http` encodes
+    /// requests with when their URL is only a path.
+    pub fn scheme(&self) -> &'static str {
+        if self.tls.is_some() {
+            "https"
+        } else {
+            ic_http::BHTTP_SCHEME
+        }
+    }
+
+    /// The URL of the gateway when it listens on the configured port.
+    pub fn listen_url(&self) -> Url {
+        self.url(self.listen_addr)
+    }
+
+    /// An HTTP client for the gateway, which trusts the generated CA if TLS is enabled.
+    pub fn client(&self) -> Result<reqwest::Client, anyhow::Error> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(ca_cert_path) = self.tls.as_ref().and_then(TlsSource::ca_cert_path)
+            && ca_cert_path.exists()
+        {
+            let ca_cert = std::fs::read(&ca_cert_path)
+                .with_context(|| format!("Failed to read {}", ca_cert_path.display()))?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&ca_cert)?);
         }
+
+        Ok(builder.build()?)
     }
 }
 
+/// A gateway bound to its listen address, ready to serve requests.
+pub struct Gateway {
+    service: IntoMakeService<Router>,
+    listener: tokio::net::TcpListener,
+    tls_config: Option<RustlsConfig>,
+    url: Url,
+    /// The background tasks of ic-gateway, which are already running.
+    tasks: TaskManager,
+    shutdown_timeout: Duration,
+    fault_injector: Arc<FaultInjector>,
+    /// The health of the background tasks of ic-gateway.
+    health_manager: Arc<HealthManager>,
+    /// Serves the legacy canisters, if some canisters may be legacy ones.
+    http_protocol_router: Option<Arc<HttpProtocolRouter>>,
+}
+
+impl Gateway {
+    pub fn local_addr(&self) -> Result<SocketAddr, anyhow::Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn fault_injector(&self) -> Arc<FaultInjector> {
+        self.fault_injector.clone()
+    }
+
+    pub fn health_manager(&self) -> Arc<HealthManager> {
+        self.health_manager.clone()
+    }
+
+    pub fn http_protocol_router(&self) -> Option<Arc<HttpProtocolRouter>> {
+        self.http_protocol_router.clone()
+    }
+
+    /// Serves requests until `shutdown_signal` completes. The gateway then stops accepting
+    /// connections, waits for the in-flight requests up to the shutdown timeout, and stops
+    /// the background tasks of ic-gateway.
+    pub async fn serve(
+        self,
+        shutdown_signal: impl Future<Output = ()> + Send + 'static,
+    ) -> std::io::Result<()> {
+        let Gateway {
+            service,
+            listener,
+            tls_config,
+            tasks,
+            shutdown_timeout,
+            ..
+        } = self;
+
+        let handle = Handle::new();
+        let shutdown_handle = handle.clone();
+        tokio::spawn(async move {
+            shutdown_signal.await;
+            // The connections still open after the timeout are closed
+            shutdown_handle.graceful_shutdown(Some(shutdown_timeout));
+            let connection_count = shutdown_handle.connection_count();
+            if connection_count > 0 {
+                println!(
+                    "Waiting up to {}s for {connection_count} open connections",
+                    shutdown_timeout.as_secs()
+                );
+            }
+        });
+
+        let listener = listener.into_std()?;
+        let result = match tls_config {
+            None => {
+                axum_server::from_tcp(listener)
+                    .handle(handle)
+                    .serve(service)
+                    .await
+            }
+            Some(tls_config) => {
+                axum_server::from_tcp_rustls(listener, tls_config)
+                    .handle(handle)
+                    .serve(service)
+                    .await
+            }
+        };
+
+        tasks.stop().await;
+
+        result
+    }
+}
+
+/// Starts the gateway. Requests matching `routes` are served by them, all other
+/// requests are forwarded to ic-gateway.
 pub async fn start_gateway(
-    listen_ip_addr: &str,
-    listen_port: u16,
+    config: &GatewayConfig,
     replica_url: &ReplicaUrl,
+    routes: Router,
     shutdown_token: CancellationToken,
-) -> Result<
-    (
-        axum::routing::IntoMakeService<Router>,
-        tokio::net::TcpListener,
-    ),
-    anyhow::Error,
-> {
-    let listen_addr = format!("{listen_ip_addr}:{listen_port}");
-
-    let mut gateway_args = vec![
-        "",
-        "--domain",
-        "localhost",
-        "--domain",
-        listen_ip_addr,
-        "--domain-canister-id-from-query-params",
-        "--domain-canister-id-from-referer",
-        "--listen-plain",
-        &listen_addr,
-    ];
-
-    match replica_url {
-        ReplicaUrl::Mainnet(url) => {
-            gateway_args.push("--ic-use-discovery");
-            gateway_args.push("--ic-url");
-            gateway_args.push(url.as_str());
+) -> Result<Gateway, anyhow::Error> {
+    let listen_addr = config.listen_addr.to_string();
+
+    let mut gateway_args = vec![String::new()];
+    for domain in config.domains() {
+        gateway_args.push("--domain".to_string());
+        gateway_args.push(domain);
+    }
+    gateway_args.extend([
+        "--domain-canister-id-from-query-params".to_string(),
+        "--domain-canister-id-from-referer".to_string(),
+        "--listen-plain".to_string(),
+        listen_addr.clone(),
+    ]);
+
+    for url in replica_url.urls() {
+        gateway_args.push("--ic-url".to_string());
+        gateway_args.push(url.to_string());
+    }
+    if replica_url.routing == Routing::Discovery {
+        gateway_args.push("--ic-use-discovery".to_string());
+    }
+    match &replica_url.root_key {
+        RootKey::Mainnet => {}
+        RootKey::Fetch => {
+            gateway_args.push("--ic-unsafe-root-key-fetch".to_string());
         }
-        ReplicaUrl::PocketIc(_) => {
-            gateway_args.push("--ic-unsafe-root-key-fetch");
+        RootKey::File(path) => {
+            gateway_args.push("--ic-root-key".to_string());
+            gateway_args.push(path.display().to_string());
         }
     }
 
-    let (router, tasks) = create_http_gateway_router(
+    gateway_args.extend(config.extra_args.iter().cloned());
+
+    let signature_debugger = config
+        .debug_signatures
+        .then(|| Arc::new(SignatureDebugger::new(config.scheme())));
+    let routes = match &signature_debugger {
+        Some(debugger) => routes.merge(signature_debug_router(debugger.clone())),
+        None => routes,
+    };
+
+    for rule in &config.faults {
+        rule.validate().map_err(anyhow::Error::msg)?;
+    }
+    let fault_injector = Arc::new(FaultInjector::new(config.faults.clone()));
+    let routes = routes
+        .merge(fault_router(fault_injector.clone()))
+        .merge(delegation_router());
+
+    let (level_handle, log_filter_handle) = init_logging(&config.log_filter, config.log_format)?;
+    let routes = routes.merge(log_filter_router(log_filter_handle));
+
+    let metrics = Arc::new(GatewayMetrics::new(config.scheme())?);
+    if let Some(metrics_listen_addr) = config.metrics_listen_addr {
+        serve_metrics(
+            metrics_listen_addr,
+            metrics.registry(),
+            shutdown_token.clone(),
+        )
+        .await?;
+    }
+
+    let (mut ic_gateway_router, tasks, health_manager) = create_http_gateway_router(
         gateway_args,
-        &replica_url.into_url(),
+        replica_url.urls(),
+        metrics.registry(),
+        level_handle,
+        fault_injector.clone(),
+        metrics.query_upgrades(),
         shutdown_token.clone(),
     )
     .await?;
 
+    // Legacy canisters are served here, the other requests go on to ic-gateway
+    let http_protocol_router = Arc::new(HttpProtocolRouter::new(
+        replica_url,
+        &config.http_protocols,
+        config.detect_http_protocol,
+        metrics.query_upgrades(),
+    )?);
+    let http_protocol_router = if http_protocol_router.is_needed() {
+        ic_gateway_router = ic_gateway_router.layer(middleware::from_fn_with_state(
+            http_protocol_router.clone(),
+            route_http_protocol,
+        ));
+        Some(http_protocol_router)
+    } else {
+        None
+    };
+
+    // Only the requests forwarded to the replica are affected by the faults and counted
+    ic_gateway_router = ic_gateway_router.layer(middleware::from_fn_with_state(
+        fault_injector.clone(),
+        inject_faults,
+    ));
+    if config.metrics_listen_addr.is_some() {
+        ic_gateway_router =
+            ic_gateway_router.layer(middleware::from_fn_with_state(metrics, count_requests));
+    }
+
+    let mut router = routes.fallback(|mut request: Request| async move {
+        let conn_info = ConnInfo::default();
+        request.extensions_mut().insert(Arc::new(conn_info));
+        ic_gateway_router.oneshot(request).await
+    });
+
+    // The admin endpoints that change the replica have no authentication
+    if !config.listen_addr.ip().is_loopback() {
+        router = router.layer(middleware::from_fn(reject_privileged_routes));
+    }
+    if let Some(debugger) = signature_debugger {
+        router = router.layer(middleware::from_fn_with_state(debugger, debug_signatures));
+    }
+    if let Some(record_file) = &config.record_file {
+        let recorder = Arc::new(Recorder::new(record_file.clone(), config.scheme())?);
+        router = router.layer(middleware::from_fn_with_state(recorder, record_traffic));
+    }
+
+    let tls_config = match &config.tls {
+        Some(tls) => {
+            // Several crates enable a rustls provider, so the one used has to be chosen
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            Some(tls.rustls_config().await?)
+        }
+        None => None,
+    };
+
     tasks.start();
 
     let listener = create_http_gateway_listener(&listen_addr)
@@ -99,7 +468,36 @@ pub async fn start_gateway(
             )
         })?;
 
-    Ok((router, listener))
+    let url = config.url(listener.local_addr()?);
+
+    Ok(Gateway {
+        service: router.into_make_service(),
+        listener,
+        tls_config,
+        url,
+        tasks,
+        shutdown_timeout: config.shutdown_timeout,
+        fault_injector,
+        health_manager,
+        http_protocol_router,
+    })
+}
+
+/// Refuses the requests to [PRIVILEGED_PATHS], used when the gateway listens on an address
+/// reachable from other hosts.
+async fn reject_privileged_routes(request: Request, next: Next) -> Response {
+    if PRIVILEGED_PATHS.contains(&request.uri().path()) {
+        return (
+            StatusCode::FORBIDDEN,
+            format!(
+                "{} is only served when the gateway listens on a loopback address",
+                request.uri().path()
+            ),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
 }
 
 async fn create_http_gateway_listener(addr: &str) -> Result<tokio::net::TcpListener, String> {
@@ -109,35 +507,34 @@ async fn create_http_gateway_listener(addr: &str) -> Result<tokio::net::TcpListe
 }
 
 async fn create_http_gateway_router(
-    args: Vec<&str>,
-    replica_url: &Url,
+    args: Vec<String>,
+    replica_urls: &[Url],
+    registry: &Registry,
+    reload_handle: reload::Handle<LevelFilter, TracingRegistry>,
+    fault_injector: Arc<FaultInjector>,
+    query_upgrades: IntCounter,
     shutdown_token: CancellationToken,
-) -> Result<(axum::routing::IntoMakeService<Router>, TaskManager), anyhow::Error> {
-    let (filter, reload_handle) = reload::Layer::new(LevelFilter::INFO);
-    TracingRegistry::default()
-        .with(filter)
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
+) -> Result<(Router, TaskManager, Arc<HealthManager>), anyhow::Error> {
     let mut tasks = TaskManager::new();
     let health_manager = Arc::new(HealthManager::default());
-    let registry = Registry::new();
 
     let cli = Cli::parse_from(args);
 
-    let (http_client, http_client_hyper) = http_clients(&registry)?;
-    let route_provider = Arc::new(RoundRobinRouteProvider::new(vec![replica_url])?);
+    let (http_client, http_client_hyper) = http_clients(registry, fault_injector, query_upgrades)?;
+    let route_provider = Arc::new(RoundRobinRouteProvider::new(
+        replica_urls.iter().map(Url::as_str).collect(),
+    )?);
 
     let ic_gateway_router = setup_router(
         &cli,
         vec![], // No custom domain providers
         reload_handle,
         &mut tasks,
-        health_manager,
+        health_manager.clone(),
         http_client,
         http_client_hyper,
         route_provider,
-        &registry,
+        registry,
         shutdown_token,
         None, // No vector
         None, // No WAF layer
@@ -145,25 +542,25 @@ async fn create_http_gateway_router(
     )
     .await?;
 
-    let router = Router::new()
-        .fallback(|mut request: Request| async move {
-            let conn_info = ConnInfo::default();
-            request.extensions_mut().insert(Arc::new(conn_info));
-            ic_gateway_router.oneshot(request).await
-        })
-        .into_make_service();
-
-    Ok((router, tasks))
+    Ok((ic_gateway_router, tasks, health_manager))
 }
 
+/// The client of the agent of ic-gateway, which counts the upgraded queries then injects the
+/// faults targeting the replica, and the client proxying the `/api` requests.
 fn http_clients(
     registry: &Registry,
-) -> Result<(Arc<ReqwestClient>, Arc<HyperClientLeastLoaded<Full<Bytes>>>), anyhow::Error> {
+    fault_injector: Arc<FaultInjector>,
+    query_upgrades: IntCounter,
+) -> Result<(Arc<FaultyClient>, Arc<HyperClientLeastLoaded<Full<Bytes>>>), anyhow::Error> {
     let dns_resolver = Resolver::new(Default::default());
-    let http_client = Arc::new(ReqwestClient::new(
+    let reqwest_client = Arc::new(ReqwestClient::new(
         Default::default(),
         Some(dns_resolver.clone()),
     )?);
+    let http_client = Arc::new(FaultyClient::new(
+        Arc::new(UpgradeCountingClient::new(reqwest_client, query_upgrades)),
+        fault_injector,
+    ));
     let http_client_hyper = Arc::new(HyperClientLeastLoaded::new(
         Default::default(),
         dns_resolver,
@@ -0,0 +1,468 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{
+        HeaderName, HeaderValue, StatusCode,
+        header::{CONTENT_LENGTH, HOST, REFERER},
+        request::Parts,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use candid::{CandidType, Decode, Encode, Func, IDLArgs, Principal, types::value::IDLValue};
+use clap::ValueEnum;
+use ic_gateway::ic_bn_lib::{
+    ic_agent::{Agent, AgentError, agent::RejectCode},
+    prometheus::IntCounter,
+    reqwest::Url,
+};
+use ic_http_certification::{
+    HttpRequest as CertifiedHttpRequest, HttpResponse as CertifiedHttpResponse, Method,
+    StatusCode as CertifiedStatusCode,
+};
+use ic_response_verification::{MIN_VERIFICATION_VERSION, verify_request_response_pair};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::sync::OnceCell;
+
+use crate::gateway::{ReplicaUrl, RootKey, read_request_body};
+
+/// The method of the bHTTP interface, whose absence marks a legacy canister.
+const V2_QUERY_METHOD: &str = "http_request_v2";
+const LEGACY_QUERY_METHOD: &str = "http_request";
+const LEGACY_UPDATE_METHOD: &str = "http_request_update";
+
+const CANISTER_ID_QUERY_PARAM: &str = "canisterId";
+const CERTIFICATE_VERSION: u16 = 2;
+
+/// The error codes of the replica for a missing method and a canister without a module.
+const METHOD_NOT_FOUND_ERROR_CODE: &str = "IC0536";
+const WASM_MODULE_NOT_FOUND_ERROR_CODE: &str = "IC0537";
+
+/// The maximum age of the certificate of a verified response, like ic-gateway.
+const MAX_CERT_TIME_OFFSET_NS: u128 = 300_000_000_000;
+
+/// The HTTP interface of a canister.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HttpProtocol {
+    /// The Candid `http_request` and `http_request_update` methods, served by the gateway of local-replica.
+    Legacy,
+    /// The bHTTP `http_request_v2` and `http_request_update_v2` methods, served by ic-gateway.
+    V2,
+}
+
+/// The HTTP protocol of a canister, parsed from `<canister-id>=<protocol>`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CanisterHttpProtocol {
+    pub canister_id: Principal,
+    pub protocol: HttpProtocol,
+}
+
+impl FromStr for CanisterHttpProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (canister_id, protocol) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected <canister-id>=<protocol>, got {s}"))?;
+        let canister_id = Principal::from_text(canister_id)
+            .map_err(|e| format!("Invalid canister id {canister_id}: {e}"))?;
+        let protocol = HttpProtocol::from_str(protocol, true)?;
+
+        Ok(Self {
+            canister_id,
+            protocol,
+        })
+    }
+}
+
+impl TryFrom<String> for CanisterHttpProtocol {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(CandidType)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: ByteBuf,
+    certificate_version: Option<u16>,
+}
+
+#[derive(CandidType)]
+struct HttpUpdateRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: ByteBuf,
+    upgrade: Option<bool>,
+    streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum StreamingStrategy {
+    Callback { callback: Func, token: IDLValue },
+}
+
+#[derive(CandidType, Deserialize)]
+struct StreamingCallbackHttpResponse {
+    body: ByteBuf,
+    token: Option<IDLValue>,
+}
+
+/// Chooses the HTTP protocol of each canister, and serves the legacy ones.
+pub struct HttpProtocolRouter {
+    agent: Agent,
+    root_key: RootKey,
+    root_key_ready: OnceCell<()>,
+    /// The configured protocols.
+    protocols: HashMap<Principal, HttpProtocol>,
+    /// The detected protocols, until the canister is deployed again.
+    detected: Mutex<HashMap<Principal, HttpProtocol>>,
+    detect: bool,
+    /// Counts the queries of legacy canisters upgraded to update calls.
+    query_upgrades: IntCounter,
+}
+
+impl HttpProtocolRouter {
+    /// Canisters without a configured protocol are detected if `detect` is set, otherwise
+    /// they are assumed to use [HttpProtocol::V2].
+    pub fn new(
+        replica_url: &ReplicaUrl,
+        protocols: &BTreeMap<Principal, HttpProtocol>,
+        detect: bool,
+        query_upgrades: IntCounter,
+    ) -> Result<Self, anyhow::Error> {
+        let agent = Agent::builder()
+            .with_url(replica_url.into_url().as_str())
+            .build()?;
+
+        Ok(Self {
+            agent,
+            root_key: replica_url.root_key.clone(),
+            root_key_ready: OnceCell::new(),
+            protocols: protocols.clone().into_iter().collect(),
+            detected: Mutex::default(),
+            detect,
+            query_upgrades,
+        })
+    }
+
+    /// Whether some canisters may be legacy ones, otherwise all requests go to ic-gateway.
+    pub fn is_needed(&self) -> bool {
+        self.detect
+            || self
+                .protocols
+                .values()
+                .any(|protocol| *protocol == HttpProtocol::Legacy)
+    }
+
+    /// Forgets the detected protocol of a canister, since a new module may implement
+    /// another interface.
+    pub fn forget_detected(&self, canister_id: Principal) {
+        self.detected.lock().unwrap().remove(&canister_id);
+    }
+
+    async fn protocol(&self, canister_id: Principal) -> HttpProtocol {
+        if let Some(protocol) = self.protocols.get(&canister_id) {
+            return *protocol;
+        }
+        if !self.detect {
+            return HttpProtocol::V2;
+        }
+        if let Some(protocol) = self.detected.lock().unwrap().get(&canister_id) {
+            return *protocol;
+        }
+
+        // Undetermined canisters go to ic-gateway, and are detected again on the next request
+        let Some(protocol) = self.detect_protocol(canister_id).await else {
+            return HttpProtocol::V2;
+        };
+        self.detected.lock().unwrap().insert(canister_id, protocol);
+        protocol
+    }
+
+    /// A canister is a legacy one if the replica rejects `http_request_v2` as a missing
+    /// method, and a v2 one if the method replies, rejects or traps on the empty argument.
+    /// Other errors, such as an unreachable replica or a canister without a module, leave
+    /// the protocol undetermined.
+    async fn detect_protocol(&self, canister_id: Principal) -> Option<HttpProtocol> {
+        self.ensure_root_key().await.ok()?;
+
+        match self
+            .agent
+            .query(&canister_id, V2_QUERY_METHOD)
+            .with_arg(vec![])
+            .call()
+            .await
+        {
+            Ok(_) => Some(HttpProtocol::V2),
+            Err(
+                AgentError::UncertifiedReject { reject, .. }
+                | AgentError::CertifiedReject { reject, .. },
+            ) => match reject.error_code.as_deref() {
+                Some(METHOD_NOT_FOUND_ERROR_CODE) => Some(HttpProtocol::Legacy),
+                Some(WASM_MODULE_NOT_FOUND_ERROR_CODE) => None,
+                _ if matches!(
+                    reject.reject_code,
+                    RejectCode::CanisterReject | RejectCode::CanisterError
+                ) =>
+                {
+                    Some(HttpProtocol::V2)
+                }
+                _ => None,
+            },
+            Err(_) => None,
+        }
+    }
+
+    async fn ensure_root_key(&self) -> Result<(), anyhow::Error> {
+        self.root_key_ready
+            .get_or_try_init(|| async {
+                match &self.root_key {
+                    RootKey::Mainnet => {}
+                    RootKey::Fetch => self.agent.fetch_root_key().await?,
+                    RootKey::File(path) => self.agent.set_root_key(std::fs::read(path)?),
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Calls `http_request`, then `http_request_update` if the canister asks for an upgrade,
+    /// and follows the streaming callbacks.
+    ///
+    /// Like ic-gateway, the responses of queries are verified against their certificate,
+    /// except the streamed ones, while the responses of update calls are certified by the
+    /// call itself.
+    async fn serve_legacy(
+        &self,
+        canister_id: Principal,
+        parts: Parts,
+        body: Bytes,
+    ) -> Result<Response, anyhow::Error> {
+        self.ensure_root_key().await?;
+
+        let url = parts
+            .uri
+            .path_and_query()
+            .map_or("/".to_string(), |path_and_query| path_and_query.to_string());
+        let headers = parts
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let query_request = HttpRequest {
+            method: parts.method.to_string(),
+            url: url.clone(),
+            headers: headers.clone(),
+            body: ByteBuf::from(body.to_vec()),
+            certificate_version: Some(CERTIFICATE_VERSION),
+        };
+        let reply = self
+            .agent
+            .query(&canister_id, LEGACY_QUERY_METHOD)
+            .with_arg(Encode!(&query_request)?)
+            .call()
+            .await?;
+        let mut response = Decode!(&reply, HttpResponse)?;
+
+        let upgraded = response.upgrade == Some(true);
+        if upgraded {
+            self.query_upgrades.inc();
+            let update_request = HttpUpdateRequest {
+                method: query_request.method.clone(),
+                url,
+                headers,
+                body: query_request.body.clone(),
+            };
+            let reply = self
+                .agent
+                .update(&canister_id, LEGACY_UPDATE_METHOD)
+                .with_arg(Encode!(&update_request)?)
+                .call_and_wait()
+                .await?;
+            response = Decode!(&reply, HttpResponse)?;
+        }
+
+        let mut body = response.body.into_vec();
+        let streamed = response.streaming_strategy.is_some();
+        if let Some(StreamingStrategy::Callback { callback, token }) = response.streaming_strategy {
+            let mut next_token = Some(token);
+            while let Some(token) = next_token {
+                let reply = self
+                    .agent
+                    .query(&callback.principal, &callback.method)
+                    .with_arg(IDLArgs::new(&[token]).to_bytes()?)
+                    .call()
+                    .await?;
+                let chunk = Decode!(&reply, StreamingCallbackHttpResponse)?;
+                body.extend_from_slice(&chunk.body);
+                next_token = chunk.token;
+            }
+        }
+
+        let headers = if upgraded || streamed {
+            response.headers
+        } else {
+            self.verify_response(
+                canister_id,
+                &query_request,
+                response.status_code,
+                response.headers,
+                &body,
+            )?
+        };
+
+        let mut http_response = Response::new(Body::from(body));
+        *http_response.status_mut() = StatusCode::from_u16(response.status_code)?;
+        for (name, value) in &headers {
+            let name = HeaderName::from_bytes(name.as_bytes())?;
+            // The body is complete, so its length is set by the server
+            if name == CONTENT_LENGTH {
+                continue;
+            }
+            http_response
+                .headers_mut()
+                .append(name, HeaderValue::from_str(value)?);
+        }
+
+        Ok(http_response)
+    }
+
+    /// Verifies the response of a query against its certificate, and returns the headers to
+    /// serve: only the certified ones if the canister certified some of them.
+    fn verify_response(
+        &self,
+        canister_id: Principal,
+        request: &HttpRequest,
+        status_code: u16,
+        headers: Vec<(String, String)>,
+        body: &[u8],
+    ) -> Result<Vec<(String, String)>, anyhow::Error> {
+        let certified_request = CertifiedHttpRequest::builder()
+            .with_method(Method::from_bytes(request.method.as_bytes())?)
+            .with_url(request.url.clone())
+            .with_headers(request.headers.clone())
+            .with_body(request.body.to_vec())
+            .build();
+        let certified_response = CertifiedHttpResponse::builder()
+            .with_status_code(CertifiedStatusCode::from_u16(status_code)?)
+            .with_headers(headers.clone())
+            .with_body(body.to_vec())
+            .build();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+
+        let verification = verify_request_response_pair(
+            certified_request,
+            certified_response,
+            canister_id.as_slice(),
+            now,
+            MAX_CERT_TIME_OFFSET_NS,
+            &self.agent.read_root_key(),
+            MIN_VERIFICATION_VERSION,
+        )
+        .context("Response verification failed")?;
+
+        // The status code isn't certified by the first version, so redirects are refused
+        if verification.verification_version < 2 {
+            anyhow::ensure!(
+                !(300..400).contains(&status_code),
+                "Response verification v1 does not allow redirects"
+            );
+            return Ok(headers);
+        }
+
+        Ok(match verification.response {
+            Some(certified) => certified.headers,
+            None => headers,
+        })
+    }
+}
+
+/// Middleware that serves the requests to legacy canisters, and forwards the other requests.
+pub async fn route_http_protocol(
+    State(router): State<Arc<HttpProtocolRouter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(canister_id) = request_canister_id(&request) else {
+        return next.run(request).await;
+    };
+    if router.protocol(canister_id).await == HttpProtocol::V2 {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = match read_request_body(body).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    router
+        .serve_legacy(canister_id, parts, body)
+        .await
+        .unwrap_or_else(|e| {
+            (
+                StatusCode::BAD_GATEWAY,
+                format!("Failed to call the legacy HTTP interface of {canister_id}: {e:#}"),
+            )
+                .into_response()
+        })
+}
+
+/// The canister id from the `<canister-id>.localhost` subdomain, the `canisterId` query
+/// parameter or the referer, like ic-gateway.
+fn request_canister_id(request: &Request) -> Option<Principal> {
+    let from_host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.split('.').next())
+        .and_then(|label| Principal::from_text(label).ok());
+    let from_query = || {
+        let url = Url::parse(&format!("http://localhost{}", request.uri())).ok()?;
+        canister_id_query_param(&url)
+    };
+    let from_referer = || {
+        let referer = request.headers().get(REFERER)?.to_str().ok()?;
+        canister_id_query_param(&Url::parse(referer).ok()?)
+    };
+
+    from_host.or_else(from_query).or_else(from_referer)
+}
+
+fn canister_id_query_param(url: &Url) -> Option<Principal> {
+    url.query_pairs()
+        .find(|(name, _)| name == CANISTER_ID_QUERY_PARAM)
+        .and_then(|(_, value)| Principal::from_text(value).ok())
+}
@@ -0,0 +1,26 @@
+//! Runs PocketIC and an HTTP gateway locally.
+//!
+//! The `replica` binary is built on top of this library, and [Environment] starts
+//! the same stack from Rust integration tests.
+
+pub mod admin;
+pub mod config;
+pub mod delegation;
+pub mod deploy;
+pub mod fault;
+pub mod gateway;
+pub mod http_protocol;
+pub mod logging;
+pub mod metrics;
+pub mod pocket_ic;
+pub mod record;
+pub mod replay;
+pub mod signature;
+pub mod signature_debug;
+pub mod state;
+pub mod tls;
+pub mod watch;
+
+mod environment;
+
+pub use environment::{Environment, EnvironmentBuilder};
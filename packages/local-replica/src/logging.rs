@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use axum::{Router, extract::State, http::StatusCode, routing::get};
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing_core::LevelFilter;
+use tracing_subscriber::{
+    EnvFilter, Registry as TracingRegistry,
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+};
+
+pub const LOG_FILTER_PATH: &str = "/_replica/log-filter";
+
+/// The subscriber below the log filter, with the level filter controlled by ic-gateway.
+type LevelFilteredRegistry = Layered<reload::Layer<LevelFilter, TracingRegistry>, TracingRegistry>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Changes the log filter of the running gateway.
+#[derive(Clone)]
+pub struct LogFilterHandle {
+    handle: reload::Handle<EnvFilter, LevelFilteredRegistry>,
+    directives: Arc<Mutex<String>>,
+}
+
+impl LogFilterHandle {
+    /// The current filter, in the `EnvFilter` syntax, e.g. `info,ic_gateway=debug`.
+    pub fn directives(&self) -> String {
+        self.directives.lock().unwrap().clone()
+    }
+
+    pub fn set_directives(&self, directives: &str) -> Result<(), anyhow::Error> {
+        let filter = parse_filter(directives)?;
+        self.handle
+            .reload(filter)
+            .context("Failed to change the log filter")?;
+        *self.directives.lock().unwrap() = directives.to_string();
+
+        Ok(())
+    }
+}
+
+/// The handles of the subscriber of the process, which is installed once.
+pub type LoggingHandles = (
+    reload::Handle<LevelFilter, TracingRegistry>,
+    LogFilterHandle,
+);
+
+static LOGGING: Mutex<Option<LoggingHandles>> = Mutex::new(None);
+
+/// Installs the subscriber of the process, filtering logs with `directives`.
+///
+/// The level filter handle is passed to ic-gateway. It's left at [LevelFilter::TRACE],
+/// so only the `EnvFilter` decides which logs are written.
+///
+/// The subscriber is only installed by the first gateway of the process, e.g. when several
+/// environments are started by the same test binary. The next ones share its handles, and
+/// their `directives` and `format` are ignored.
+pub fn init_logging(directives: &str, format: LogFormat) -> Result<LoggingHandles, anyhow::Error> {
+    let mut logging = LOGGING.lock().unwrap();
+    if let Some(handles) = logging.as_ref() {
+        return Ok(handles.clone());
+    }
+
+    let (level_filter, level_handle) = reload::Layer::new(LevelFilter::TRACE);
+    let (filter, filter_handle) = reload::Layer::new(parse_filter(directives)?);
+
+    TracingRegistry::default()
+        .with(level_filter)
+        .with(filter)
+        .with((format == LogFormat::Text).then(tracing_subscriber::fmt::layer))
+        .with((format == LogFormat::Json).then(|| tracing_subscriber::fmt::layer().json()))
+        .try_init()
+        .context("Failed to install the log subscriber")?;
+
+    let handles = (
+        level_handle,
+        LogFilterHandle {
+            handle: filter_handle,
+            directives: Arc::new(Mutex::new(directives.to_string())),
+        },
+    );
+    *logging = Some(handles.clone());
+
+    Ok(handles)
+}
+
+fn parse_filter(directives: &str) -> Result<EnvFilter, anyhow::Error> {
+    EnvFilter::try_new(directives).with_context(|| format!("Invalid log filter: {directives}"))
+}
+
+/// Serves the log filter on [LOG_FILTER_PATH], and replaces it with the body of `PUT` requests.
+pub fn log_filter_router(handle: LogFilterHandle) -> Router {
+    Router::new()
+        .route(
+            LOG_FILTER_PATH,
+            get(log_filter_handler).put(set_log_filter_handler),
+        )
+        .with_state(handle)
+}
+
+async fn log_filter_handler(State(handle): State<LogFilterHandle>) -> String {
+    handle.directives()
+}
+
+async fn set_log_filter_handler(
+    State(handle): State<LogFilterHandle>,
+    directives: String,
+) -> Result<String, (StatusCode, String)> {
+    handle
+        .set_directives(directives.trim())
+        .map(|()| handle.directives())
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))
+}
@@ -1,78 +1,844 @@
-mod gateway;
-mod pocket_ic;
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use clap::Parser;
-use ic_gateway::ic_bn_lib::reqwest::Url;
+use anyhow::Context;
+use candid::Principal;
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use ic_gateway::ic_bn_lib::reqwest::{self, Url};
+use pocket_ic::nonblocking::PocketIc;
 use tokio_util::sync::CancellationToken;
+use tracing_core::LevelFilter;
 
-use crate::{
-    gateway::{ReplicaUrl, start_gateway},
-    pocket_ic::start_pocket_ic,
+use local_replica::{
+    admin::{AdminState, CANISTERS_PATH, READY_PATH, Readiness, admin_router},
+    config::Config,
+    delegation::{DEFAULT_DELEGATION_EXPIRATION, DelegationRequest, issue_delegation},
+    deploy::{CanisterDeployment, DeployedCanister},
+    gateway::{
+        DEFAULT_SHUTDOWN_TIMEOUT, GatewayConfig, ReplicaUrl, RootKey, Routing, start_gateway,
+    },
+    http_protocol::CanisterHttpProtocol,
+    logging::LogFormat,
+    metrics::METRICS_PATH,
+    pocket_ic::{
+        DEFAULT_APPLICATION_SUBNETS, IcpFeature, PocketIcConfig, start_pocket_ic, stop_pocket_ic,
+        take_pocket_ic,
+    },
+    record::read_recording,
+    replay::{DEFAULT_COMPARED_HEADERS, ReplayOptions, load_identity, replay},
+    state::StateDir,
+    tls::TlsSource,
+    watch::{WatchTarget, watch_canister},
 };
 
-const PACKAGE_DIR: &str = env!("CARGO_MANIFEST_DIR");
-const POCKET_IC_SERVER_BIN_PATH: &str = "bin/pocket-ic";
-
-const LOCAL_REPLICA_HTTP_LISTEN_IP_ADDR: &str = "127.0.0.1";
+const LOCAL_REPLICA_HTTP_LISTEN_IP_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
 const LOCAL_REPLICA_HTTP_LISTEN_PORT: u16 = 4943;
 
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const DEFAULT_CANISTER_IDS_FILE: &str = ".replica/canister_ids.json";
+const DEFAULT_TLS_DIR: &str = ".replica/tls";
+
 #[derive(Parser, Debug)]
 #[command(name = "replica")]
 #[command(about = "Local replica server with HTTP gateway", long_about = None)]
 struct Args {
-    /// Replica URL to use (if set, the PocketIC server won't be started)
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Replica URL to use, can be repeated to balance the requests (if set, the PocketIC server won't be started)
     #[arg(long, value_name = "URL")]
-    replica_url: Option<String>,
+    replica_url: Vec<String>,
+
+    /// DER encoded root key of the replica [default: the mainnet root key]
+    #[arg(long, value_name = "PATH")]
+    root_key: Option<PathBuf>,
+
+    /// Fetch the root key from the replica, e.g. one started by dfx (only safe with a local or test replica)
+    #[arg(long, overrides_with = "no_fetch_root_key")]
+    fetch_root_key: bool,
+
+    /// Don't fetch the root key, even if the config file sets `fetch-root-key`
+    #[arg(long, overrides_with = "fetch_root_key")]
+    no_fetch_root_key: bool,
+
+    /// How requests are sent to the replica URLs [default: discovery with the mainnet root key, static otherwise]
+    #[arg(long, value_name = "ROUTING")]
+    routing: Option<Routing>,
+
+    /// Path to a TOML config file with the same settings as the command line flags
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// IP address the gateway listens on [default: 127.0.0.1]
+    #[arg(long, value_name = "IP")]
+    listen: Option<IpAddr>,
+
+    /// Port the gateway listens on [default: 4943]
+    #[arg(long, value_name = "PORT")]
+    port: Option<u16>,
+
+    /// Domain served by the gateway, can be repeated [default: localhost and the listen IP]
+    #[arg(long = "domain", value_name = "DOMAIN")]
+    domains: Vec<String>,
+
+    /// Log filter of the gateway, a level or `EnvFilter` directives such as `info,ic_gateway=debug` [default: info]
+    #[arg(long, value_name = "FILTER")]
+    log_level: Option<String>,
+
+    /// Format of the gateway logs [default: text]
+    #[arg(long, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// Record the requests and responses of the gateway to a JSONL file, or a HAR file if the path ends with `.har`
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Log a report on the signature of every signed request, also served on /_debug/last-requests
+    #[arg(long, overrides_with = "no_debug_signatures")]
+    debug_signatures: bool,
+
+    /// Don't check the signatures, even if the config file sets `debug-signatures`
+    #[arg(long, overrides_with = "debug_signatures")]
+    no_debug_signatures: bool,
+
+    /// Serve HTTPS with a certificate for localhost and *.localhost, signed by a local CA generated in .replica/tls
+    #[arg(long, overrides_with = "no_tls")]
+    tls: bool,
+
+    /// Serve HTTP with the generated certificate, even if the config file sets `tls`
+    #[arg(long, overrides_with = "tls")]
+    no_tls: bool,
+
+    /// PEM certificate chain served instead of the generated one, implies --tls
+    #[arg(long, value_name = "PEM")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long, value_name = "PEM")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve the Prometheus metrics of the gateway on /metrics at this address, e.g. 127.0.0.1:9090
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// HTTP interface of a canister, `legacy` for `http_request` or `v2` for bHTTP, can be repeated [default: detected]
+    #[arg(long, value_name = "CANISTER_ID=PROTOCOL")]
+    http_protocol: Vec<CanisterHttpProtocol>,
+
+    /// Don't detect the HTTP interface of canisters, and use v2 for the ones without --http-protocol
+    #[arg(long)]
+    no_detect_http_protocol: bool,
+
+    /// Maximum number of seconds to wait for in-flight requests on shutdown [default: 10]
+    #[arg(long, value_name = "SECONDS")]
+    shutdown_timeout: Option<u64>,
+
+    /// Directory where the PocketIC state is loaded from on startup and saved to on shutdown
+    #[arg(long, value_name = "DIR", global = true)]
+    state_dir: Option<PathBuf>,
+
+    /// File where the ids of the deployed canisters are written, in the `canister_ids.json` format [default: .replica/canister_ids.json]
+    #[arg(long, value_name = "PATH")]
+    canister_ids_file: Option<PathBuf>,
+
+    /// Upgrade the canister every time the wasm file changes, can be repeated
+    #[arg(long, value_name = "WASM_PATH=CANISTER_ID")]
+    watch: Vec<WatchTarget>,
+
+    /// Path to the PocketIC server binary [default: bin/pocket-ic in the package directory]
+    #[arg(long, value_name = "PATH", help_heading = "PocketIC")]
+    pocket_ic_bin: Option<PathBuf>,
+
+    /// Add an NNS subnet
+    #[arg(long, overrides_with = "no_nns_subnet", help_heading = "PocketIC")]
+    nns_subnet: bool,
+
+    /// Don't add an NNS subnet, even if the config file sets `nns-subnet`
+    #[arg(long, overrides_with = "nns_subnet", help_heading = "PocketIC")]
+    no_nns_subnet: bool,
+
+    /// Number of system subnets [default: 0]
+    #[arg(long, value_name = "COUNT", help_heading = "PocketIC")]
+    system_subnets: Option<usize>,
+
+    /// Add a fiduciary subnet
+    #[arg(
+        long,
+        overrides_with = "no_fiduciary_subnet",
+        help_heading = "PocketIC"
+    )]
+    fiduciary_subnet: bool,
+
+    /// Don't add a fiduciary subnet, even if the config file sets `fiduciary-subnet`
+    #[arg(long, overrides_with = "fiduciary_subnet", help_heading = "PocketIC")]
+    no_fiduciary_subnet: bool,
+
+    /// Number of application subnets [default: 1]
+    #[arg(long, value_name = "COUNT", help_heading = "PocketIC")]
+    application_subnets: Option<usize>,
+
+    /// Enable an ICP feature, can be repeated [default: cycles-minting, cycles-token and ii]
+    #[arg(
+        long = "enable-feature",
+        value_name = "FEATURE",
+        help_heading = "PocketIC"
+    )]
+    enable_features: Vec<IcpFeature>,
+
+    /// Disable an ICP feature that is enabled by default, can be repeated
+    #[arg(
+        long = "disable-feature",
+        value_name = "FEATURE",
+        help_heading = "PocketIC"
+    )]
+    disable_features: Vec<IcpFeature>,
+
+    /// Additional flags passed to ic-gateway as is, after a `--` separator
+    #[arg(last = true, value_name = "GATEWAY_ARGS")]
+    gateway_args: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage named snapshots of the PocketIC state, while the replica is stopped
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+    /// Install a wasm module in the running replica, or upgrade it if the canister already exists
+    Deploy(DeployCommand),
+    /// Wait until the running replica is ready to serve requests
+    Wait(WaitCommand),
+    /// Resend the requests of a recording to the running replica and compare the responses
+    Replay(ReplayCommand),
+    /// Issue a delegation chain for a session key, like Internet Identity, and print it as JSON
+    Delegation(DelegationCommand),
+}
+
+#[derive(ClapArgs, Debug)]
+struct DeployCommand {
+    /// Path to the wasm module, optionally gzipped
+    #[arg(long, value_name = "PATH")]
+    wasm: PathBuf,
+
+    /// Hex encoded Candid init or upgrade argument [default: ()]
+    #[arg(long, value_name = "HEX")]
+    init_arg: Option<String>,
+
+    /// Install the module in this canister, creating it if it doesn't exist
+    #[arg(long, value_name = "PRINCIPAL")]
+    canister_id: Option<Principal>,
+
+    /// Name of the canister in the canister ids file [default: the wasm file name]
+    #[arg(long)]
+    name: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Save the state persisted on the last shutdown as a snapshot, refused while the replica runs
+    Save { name: String },
+    /// Replace the state with a snapshot, to be loaded on the next startup
+    Restore { name: String },
+    /// List the saved snapshots
+    List,
+}
+
+#[derive(ClapArgs, Debug)]
+struct WaitCommand {
+    /// Maximum number of seconds to wait
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    timeout: u64,
+}
+
+#[derive(ClapArgs, Debug)]
+struct ReplayCommand {
+    /// Recording written with --record
+    file: PathBuf,
+
+    /// Sign the signed requests again with the identity in this PEM file, since the recorded signatures expire
+    #[arg(long, value_name = "PEM")]
+    identity: Option<PathBuf>,
+
+    /// Response header to compare, can be repeated [default: content-type]
+    #[arg(long = "compare-header", value_name = "HEADER")]
+    compared_headers: Vec<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct DelegationCommand {
+    /// The same user always gets the same principal
+    #[arg(long, default_value = "default")]
+    user: String,
+
+    /// Base64 encoded DER public key of the session [default: a generated P-256 key, printed with its private key]
+    #[arg(long, value_name = "BASE64")]
+    session_public_key: Option<String>,
+
+    /// Seconds until the delegation expires
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_DELEGATION_EXPIRATION.as_secs())]
+    expiration: u64,
+
+    /// Restrict the delegation to this canister, can be repeated [default: all canisters]
+    #[arg(long = "target", value_name = "PRINCIPAL")]
+    targets: Vec<Principal>,
+}
+
+/// A boolean set on the command line with `--<name>` or `--no-<name>`, which takes precedence
+/// over the config file, or `None` if neither is passed.
+fn flag(yes: bool, no: bool) -> Option<bool> {
+    match (yes, no) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+impl Args {
+    /// Merges the gateway flags with the config file, command line flags taking precedence.
+    fn gateway_config(&self, config: &Config) -> Result<GatewayConfig, anyhow::Error> {
+        let listen_ip = self
+            .listen
+            .or(config.listen)
+            .unwrap_or(LOCAL_REPLICA_HTTP_LISTEN_IP_ADDR);
+        let listen_port = self
+            .port
+            .or(config.port)
+            .unwrap_or(LOCAL_REPLICA_HTTP_LISTEN_PORT);
+
+        let domains = if self.domains.is_empty() {
+            config.domains.clone()
+        } else {
+            self.domains.clone()
+        };
+
+        let log_filter = self
+            .log_level
+            .clone()
+            .or_else(|| config.log_level.clone())
+            .unwrap_or_else(|| LevelFilter::INFO.to_string());
+
+        Ok(GatewayConfig {
+            listen_addr: SocketAddr::new(listen_ip, listen_port),
+            domains,
+            log_filter,
+            log_format: self.log_format.or(config.log_format).unwrap_or_default(),
+            extra_args: [config.gateway_args.clone(), self.gateway_args.clone()].concat(),
+            record_file: self.record.clone().or_else(|| config.record.clone()),
+            debug_signatures: flag(self.debug_signatures, self.no_debug_signatures)
+                .unwrap_or(config.debug_signatures),
+            tls: self.tls_source(config)?,
+            shutdown_timeout: self
+                .shutdown_timeout
+                .or(config.shutdown_timeout)
+                .map_or(DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs),
+            faults: config.faults.clone(),
+            metrics_listen_addr: self.metrics_listen.or(config.metrics_listen),
+            http_protocols: config
+                .http_protocols
+                .clone()
+                .into_iter()
+                .chain(
+                    self.http_protocol
+                        .iter()
+                        .map(|canister| (canister.canister_id, canister.protocol)),
+                )
+                .collect(),
+            detect_http_protocol: !self.no_detect_http_protocol
+                && config.detect_http_protocol.unwrap_or(true),
+        })
+    }
+
+    fn tls_source(&self, config: &Config) -> Result<Option<TlsSource>, anyhow::Error> {
+        let cert = self.tls_cert.clone().or_else(|| config.tls_cert.clone());
+        let key = self.tls_key.clone().or_else(|| config.tls_key.clone());
+
+        match (cert, key) {
+            (Some(cert), Some(key)) => Ok(Some(TlsSource::Files { cert, key })),
+            (Some(_), None) | (None, Some(_)) => {
+                anyhow::bail!("The TLS certificate and key must be set together")
+            }
+            (None, None) if flag(self.tls, self.no_tls).unwrap_or(config.tls) => {
+                Ok(Some(TlsSource::Generated {
+                    dir: PathBuf::from(DEFAULT_TLS_DIR),
+                }))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Merges the replica flags with the config file, or returns `None` if PocketIC should be started.
+    fn remote_replica_url(&self, config: &Config) -> Result<Option<ReplicaUrl>, anyhow::Error> {
+        let urls = if self.replica_url.is_empty() {
+            &config.replica_url
+        } else {
+            &self.replica_url
+        };
+        let root_key_file = self.root_key.clone().or_else(|| config.root_key.clone());
+        let fetch_root_key =
+            flag(self.fetch_root_key, self.no_fetch_root_key).unwrap_or(config.fetch_root_key);
+
+        if urls.is_empty() {
+            if root_key_file.is_some() || fetch_root_key {
+                anyhow::bail!("The root key can only be set with a replica URL");
+            }
+            return Ok(None);
+        }
+
+        if !self.watch_targets(config).is_empty() {
+            anyhow::bail!(
+                "Watching wasm files requires PocketIC, it can't be used with a replica URL"
+            );
+        }
+        if self.state_dir(config).is_some() {
+            anyhow::bail!(
+                "The state directory requires PocketIC, it can't be used with a replica URL"
+            );
+        }
+
+        let urls = urls
+            .iter()
+            .map(|url| Url::parse(url).with_context(|| format!("Invalid replica URL {url}")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let root_key = match (root_key_file, fetch_root_key) {
+            (Some(_), true) => {
+                anyhow::bail!("A root key file can't be used when fetching the root key")
+            }
+            (Some(path), false) => RootKey::File(path),
+            (None, true) => RootKey::Fetch,
+            (None, false) => RootKey::Mainnet,
+        };
+        let routing = self.routing.or(config.routing).unwrap_or(match root_key {
+            RootKey::Mainnet => Routing::Discovery,
+            RootKey::Fetch | RootKey::File(_) => Routing::Static,
+        });
+
+        Ok(Some(ReplicaUrl::new(urls, root_key, routing)?))
+    }
+
+    /// Merges the PocketIC flags with the config file, command line flags taking precedence.
+    fn pocket_ic_config(&self, config: &Config) -> PocketIcConfig {
+        let section = &config.pocket_ic;
+
+        let server_binary = self
+            .pocket_ic_bin
+            .clone()
+            .or_else(|| section.server_binary.clone())
+            .unwrap_or_else(PocketIcConfig::default_server_binary);
+
+        let mut icp_features = BTreeSet::from(IcpFeature::DEFAULTS);
+        for feature in section
+            .disable_features
+            .iter()
+            .chain(&self.disable_features)
+        {
+            icp_features.remove(feature);
+        }
+        icp_features.extend(section.enable_features.iter().chain(&self.enable_features));
+
+        PocketIcConfig {
+            server_binary,
+            nns_subnet: flag(self.nns_subnet, self.no_nns_subnet).unwrap_or(section.nns_subnet),
+            system_subnets: self.system_subnets.or(section.system_subnets).unwrap_or(0),
+            fiduciary_subnet: flag(self.fiduciary_subnet, self.no_fiduciary_subnet)
+                .unwrap_or(section.fiduciary_subnet),
+            application_subnets: self
+                .application_subnets
+                .or(section.application_subnets)
+                .unwrap_or(DEFAULT_APPLICATION_SUBNETS),
+            icp_features,
+            state_dir: self.state_dir(config),
+        }
+    }
+
+    fn canister_ids_file(&self, config: &Config) -> PathBuf {
+        self.canister_ids_file
+            .clone()
+            .or_else(|| config.canister_ids_file.clone())
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CANISTER_IDS_FILE))
+    }
+
+    fn watch_targets(&self, config: &Config) -> Vec<WatchTarget> {
+        [config.watch.clone(), self.watch.clone()].concat()
+    }
+
+    fn state_dir(&self, config: &Config) -> Option<StateDir> {
+        self.state_dir
+            .clone()
+            .or_else(|| config.state_dir.clone())
+            .map(StateDir::new)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    match args.command.take() {
+        Some(Command::Snapshot(command)) => run_snapshot_command(&command, &args, &config)?,
+        Some(Command::Deploy(command)) => run_deploy_command(command, &args, &config).await?,
+        Some(Command::Wait(command)) => run_wait_command(command, &args, &config).await?,
+        Some(Command::Replay(command)) => run_replay_command(command, &args, &config).await?,
+        Some(Command::Delegation(command)) => run_delegation_command(command)?,
+        None => run_replica(args, config).await?,
+    }
+
+    Ok(())
+}
+
+fn run_snapshot_command(
+    command: &SnapshotCommand,
+    args: &Args,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let state_dir = args
+        .state_dir(config)
+        .ok_or_else(|| anyhow::anyhow!("A state directory must be set with --state-dir"))?;
+
+    match command {
+        SnapshotCommand::Save { name } => {
+            state_dir.save_snapshot(name)?;
+            println!("Saved snapshot {name}");
+        }
+        SnapshotCommand::Restore { name } => {
+            state_dir.restore_snapshot(name)?;
+            println!("Restored snapshot {name}");
+        }
+        SnapshotCommand::List => {
+            for name in state_dir.list_snapshots()? {
+                println!("{name}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends the deployment to the admin endpoint of the running replica.
+async fn run_deploy_command(
+    command: DeployCommand,
+    args: &Args,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let gateway_config = args.gateway_config(config)?;
+    let wasm = std::fs::canonicalize(&command.wasm)
+        .with_context(|| format!("Failed to find wasm module {}", command.wasm.display()))?;
+    let deployment = CanisterDeployment {
+        name: command.name,
+        wasm,
+        init_arg: command.init_arg,
+        canister_id: command.canister_id,
+    };
+
+    let url = gateway_config.listen_url().join(CANISTERS_PATH)?;
+    let response = gateway_config
+        .client()?
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&deployment)?)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach the replica at {url}, is it running?"))?;
+
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        anyhow::bail!(
+            "Failed to deploy canister: {}",
+            String::from_utf8_lossy(&body)
+        );
+    }
+
+    print_deployed_canister(&serde_json::from_slice(&body)?);
+
+    Ok(())
+}
+
+/// Polls the readiness endpoint of the running replica until it's ready or the timeout expires.
+async fn run_wait_command(
+    command: WaitCommand,
+    args: &Args,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let gateway_config = args.gateway_config(config)?;
+    let url = gateway_config.listen_url().join(READY_PATH)?;
+    let client = gateway_config.client()?;
+    let deadline = Instant::now() + Duration::from_secs(command.timeout);
+
+    let mut last_status = "the replica is not reachable".to_string();
+    while Instant::now() < deadline {
+        if let Ok(response) = client.get(url.clone()).send().await {
+            let is_ready = response.status().is_success();
+            if let Ok(readiness) = serde_json::from_slice::<Readiness>(&response.bytes().await?) {
+                if is_ready {
+                    println!(
+                        "Replica is ready with {} canisters",
+                        readiness.canisters.len()
+                    );
+                    return Ok(());
+                }
+
+                last_status = [readiness.replica, readiness.root_key, readiness.startup]
+                    .into_iter()
+                    .filter(|check| !check.ok)
+                    .map(|check| check.detail)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+            }
+        }
+
+        tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+    }
+
+    anyhow::bail!(
+        "The replica was not ready after {}s: {last_status}",
+        command.timeout
+    )
+}
+
+async fn run_replay_command(
+    command: ReplayCommand,
+    args: &Args,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let gateway_config = args.gateway_config(config)?;
+    let exchanges = read_recording(&command.file)?;
+    let compared_headers = if command.compared_headers.is_empty() {
+        DEFAULT_COMPARED_HEADERS.map(str::to_string).to_vec()
+    } else {
+        command.compared_headers
+    };
+    let identity = command.identity.as_deref().map(load_identity).transpose()?;
+
+    let results = replay(
+        &exchanges,
+        &ReplayOptions {
+            gateway_url: gateway_config.listen_url(),
+            client: gateway_config.client()?,
+            compared_headers,
+            identity,
+        },
+    )
+    .await?;
+
+    let mut num_different = 0;
+    for result in &results {
+        if result.differences.is_empty() {
+            println!("OK   {} {}", result.method, result.uri);
+            continue;
+        }
+
+        num_different += 1;
+        println!("DIFF {} {}", result.method, result.uri);
+        for difference in &result.differences {
+            println!("  {}:", difference.field);
+            println!("    expected: {}", difference.expected);
+            println!("    actual:   {}", difference.actual);
+        }
+    }
+
+    println!(
+        "{} of {} responses matched the recording",
+        results.len() - num_different,
+        results.len()
+    );
+    if num_different > 0 {
+        anyhow::bail!("{num_different} responses differ from the recording");
+    }
+
+    Ok(())
+}
+
+fn run_delegation_command(command: DelegationCommand) -> Result<(), anyhow::Error> {
+    let issued = issue_delegation(&DelegationRequest {
+        user: command.user,
+        session_public_key: command.session_public_key,
+        expiration_secs: command.expiration,
+        targets: command.targets,
+    })?;
+    println!("{}", serde_json::to_string_pretty(&issued)?);
+
+    Ok(())
+}
+
+fn print_deployed_canister(deployed: &DeployedCanister) {
+    let action = if deployed.upgraded {
+        "Upgraded"
+    } else {
+        "Installed"
+    };
+    println!("{action} {} as {}", deployed.name, deployed.canister_id);
+    for url in &deployed.urls {
+        println!("  {url}");
+    }
+}
+
+async fn run_replica(args: Args, config: Config) -> Result<(), Box<dyn std::error::Error>> {
     let shutdown_token = CancellationToken::new();
 
-    // Determine replica URL and optionally start PocketIC server
-    let (replica_url, pic_handle) = if let Some(url_str) = args.replica_url {
+    let gateway_config = args.gateway_config(&config)?;
+    let pocket_ic_config = args.pocket_ic_config(&config);
+    let canister_ids_file = args.canister_ids_file(&config);
+    let watch_targets = args.watch_targets(&config);
+
+    // Determine replica URL and optionally start PocketIC server. The state directory stays
+    // locked until PocketIC has saved its state on shutdown.
+    let mut state_lock = None;
+    let (replica_url, pic_handle) = if let Some(replica_url) = args.remote_replica_url(&config)? {
         // Use provided replica URL, don't start PocketIC
-        let url = Url::parse(&url_str)?;
-        println!("Using replica URL: {}", url_str);
-        (ReplicaUrl::new_remote(url), None)
+        for url in replica_url.urls() {
+            println!("Using replica URL: {url}");
+        }
+        (replica_url, None)
     } else {
         // Start PocketIC server if no replica URL is provided
-        let pic_path = format!("{PACKAGE_DIR}/{POCKET_IC_SERVER_BIN_PATH}");
-        let (pic, pic_url) = start_pocket_ic(&pic_path).await;
+        state_lock = pocket_ic_config
+            .state_dir
+            .as_ref()
+            .map(StateDir::lock)
+            .transpose()?;
+        let (pic, pic_url) = start_pocket_ic(&pocket_ic_config).await?;
         println!("PocketIC Server URL: {}", pic_url);
         (ReplicaUrl::new_pocket_ic(pic_url), Some(pic))
     };
 
+    let pic_handle = pic_handle.map(Arc::new);
+
+    // PocketIC is stopped whether the gateway stopped normally or failed, so that the state
+    // directory is saved in both cases
+    let result = serve_replica(
+        &gateway_config,
+        &config,
+        pic_handle.clone(),
+        replica_url,
+        canister_ids_file,
+        watch_targets,
+        shutdown_token,
+    )
+    .await;
+
+    let stopped = match pic_handle {
+        Some(pic) => match take_pocket_ic(pic, gateway_config.shutdown_timeout).await {
+            Ok(pic) => {
+                stop_pocket_ic(pic, &pocket_ic_config).await;
+                println!("PocketIC server stopped");
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+        None => Ok(()),
+    };
+    drop(state_lock);
+
+    result?;
+    stopped?;
+    Ok(())
+}
+
+/// Serves the gateway until the shutdown signal, then waits for the watchers to stop. Every
+/// reference to PocketIC held by the admin endpoints and the watchers is released on return.
+async fn serve_replica(
+    gateway_config: &GatewayConfig,
+    config: &Config,
+    pic_handle: Option<Arc<PocketIc>>,
+    replica_url: ReplicaUrl,
+    canister_ids_file: PathBuf,
+    watch_targets: Vec<WatchTarget>,
+    shutdown_token: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
     // Setup gateway
-    let (router, listener) = start_gateway(
-        LOCAL_REPLICA_HTTP_LISTEN_IP_ADDR,
-        LOCAL_REPLICA_HTTP_LISTEN_PORT,
+    let admin_state = AdminState::new(
+        pic_handle,
+        replica_url.clone(),
+        gateway_config.listen_url(),
+        gateway_config.domains(),
+        canister_ids_file,
+    )?;
+    let gateway = start_gateway(
+        gateway_config,
         &replica_url,
+        admin_router(admin_state.clone()),
         shutdown_token.clone(),
     )
     .await?;
+    admin_state.set_gateway_health(gateway.health_manager());
+    if let Some(router) = gateway.http_protocol_router() {
+        admin_state.set_http_protocol_router(router);
+    }
+
+    println!("Gateway running at: {}", gateway.url());
+    if let Some(metrics_listen_addr) = gateway_config.metrics_listen_addr {
+        println!("Metrics served at: http://{metrics_listen_addr}{METRICS_PATH}");
+    }
+    if let Some(ca_cert_path) = gateway_config
+        .tls
+        .as_ref()
+        .and_then(TlsSource::ca_cert_path)
+    {
+        println!("Local CA certificate: {}", ca_cert_path.display());
+    }
+
+    for deployment in &config.canisters {
+        let deployed = admin_state.deploy(deployment).await?;
+        print_deployed_canister(&deployed);
+    }
+    admin_state.set_started();
+    let watch_tasks = watch_targets
+        .into_iter()
+        .map(|target| watch_canister(admin_state.clone(), target, shutdown_token.clone()))
+        .collect::<Vec<_>>();
 
-    println!(
-        "Gateway running at: http://{}",
-        listener.local_addr().unwrap()
-    );
     println!("Press Ctrl+C to stop");
 
     // Setup graceful shutdown signal
+    let signal_token = shutdown_token.clone();
     let shutdown_signal = async move {
-        tokio::signal::ctrl_c().await.ok();
+        wait_for_shutdown_signal().await;
         println!("\nShutting down...");
-        shutdown_token.cancel();
-        if let Some(pic) = pic_handle {
-            pic.drop().await;
-            println!("PocketIC server stopped");
-        }
+        signal_token.cancel();
     };
 
-    axum::serve(listener, router)
-        .with_graceful_shutdown(shutdown_signal)
-        .await?;
+    let served = gateway.serve(shutdown_signal).await;
+    println!("Gateway stopped");
+
+    // The watchers also stop when the gateway failed
+    shutdown_token.cancel();
+    for task in watch_tasks {
+        task.await.ok();
+    }
+    served?;
 
     Ok(())
 }
+
+/// Completes on Ctrl+C, or on SIGTERM, which is sent by container runtimes and process supervisors.
+async fn wait_for_shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
@@ -0,0 +1,267 @@
+use std::{io::Cursor, net::SocketAddr, sync::Arc};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use axum::{
+    Router,
+    body::Body,
+    extract::{Request, State},
+    http::{StatusCode, header::CONTENT_TYPE},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use bhttp::Message;
+use ic_bn_lib_common::traits::http::Client;
+use ic_gateway::ic_bn_lib::{
+    prometheus::{Encoder, IntCounter, IntCounterVec, Opts, Registry, TextEncoder},
+    reqwest,
+};
+use ic_http::UPGRADE_HEADER_NAME;
+use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    gateway::read_request_body, signature::SIGNATURE_INPUT_HEADER_NAME,
+    signature_debug::signature_report,
+};
+
+pub const METRICS_PATH: &str = "/metrics";
+
+/// The path suffix of the queries the agent of ic-gateway sends to the replica.
+const QUERY_PATH_SUFFIX: &str = "/query";
+
+/// The metrics of ic-gateway, along with counters on the signed requests.
+pub struct GatewayMetrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    query_upgrades: IntCounter,
+    signature_failures: IntCounterVec,
+    /// The scheme of the gateway, to check the signatures of the requests.
+    scheme: String,
+}
+
+impl GatewayMetrics {
+    pub fn new(scheme: &str) -> Result<Self, anyhow::Error> {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "local_replica_requests_total",
+                "Requests forwarded to the replica, by whether they have a signature",
+            ),
+            &["signed"],
+        )?;
+        let query_upgrades = IntCounter::new(
+            "local_replica_query_upgrades_total",
+            "Queries whose response asks for an update call",
+        )?;
+        let signature_failures = IntCounterVec::new(
+            Opts::new(
+                "local_replica_signature_failures_total",
+                "Signed requests that fail a signature check, by the first failed check",
+            ),
+            &["check"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(query_upgrades.clone()))?;
+        registry.register(Box::new(signature_failures.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            query_upgrades,
+            signature_failures,
+            scheme: scheme.to_string(),
+        })
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// The counter of the queries upgraded to update calls, shared with the clients that see
+    /// the responses of the canisters, see [UpgradeCountingClient].
+    pub fn query_upgrades(&self) -> IntCounter {
+        self.query_upgrades.clone()
+    }
+}
+
+/// Serves the registry on [METRICS_PATH] at `addr`, until the shutdown token is cancelled.
+pub async fn serve_metrics(
+    addr: SocketAddr,
+    registry: &Registry,
+    shutdown_token: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to create metrics listener for address: {addr}"))?;
+    let router = Router::new()
+        .route(METRICS_PATH, get(metrics_handler))
+        .with_state(registry.clone());
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown_token.cancelled_owned())
+            .await
+        {
+            eprintln!("Failed to serve metrics: {e}");
+        }
+    });
+
+    Ok(())
+}
+
+async fn metrics_handler(State(registry): State<Registry>) -> Response {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    match encoder.encode(&registry.gather(), &mut buffer) {
+        Ok(()) => ([(CONTENT_TYPE, encoder.format_type().to_string())], buffer).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to encode metrics: {e}"),
+        )
+            .into_response(),
+    }
+}
+
+/// Middleware that counts the requests, and checks the signature of the signed ones.
+pub async fn count_requests(
+    State(metrics): State<Arc<GatewayMetrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let signed = request.headers().contains_key(SIGNATURE_INPUT_HEADER_NAME);
+    metrics
+        .requests
+        .with_label_values(&[if signed { "true" } else { "false" }])
+        .inc();
+
+    let request = if signed {
+        let (parts, body) = request.into_parts();
+        let body = match read_request_body(body).await {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+
+        if let Some(check) = signature_report(&parts, &body, &metrics.scheme).failed_check {
+            metrics
+                .signature_failures
+                .with_label_values(&[check.as_str()])
+                .inc();
+        }
+
+        Request::from_parts(parts, Body::from(body))
+    } else {
+        request
+    };
+
+    next.run(request).await
+}
+
+/// The reply of a query in the CBOR response of the replica.
+#[derive(Deserialize)]
+struct QueryResponse {
+    reply: Option<QueryReply>,
+}
+
+#[derive(Deserialize)]
+struct QueryReply {
+    #[serde(with = "serde_bytes")]
+    arg: Vec<u8>,
+}
+
+/// The HTTP client ic-gateway sends its requests to the replica with, which counts the queries
+/// whose bHTTP response has an `ic-upgrade: true` header.
+///
+/// ic-gateway handles the header itself and sends the update call right after, so the
+/// upgrade is only visible between ic-gateway and the replica.
+#[derive(Debug)]
+pub struct UpgradeCountingClient {
+    inner: Arc<dyn Client>,
+    query_upgrades: IntCounter,
+}
+
+impl UpgradeCountingClient {
+    pub fn new(inner: Arc<dyn Client>, query_upgrades: IntCounter) -> Self {
+        Self {
+            inner,
+            query_upgrades,
+        }
+    }
+}
+
+#[async_trait]
+impl Client for UpgradeCountingClient {
+    async fn execute(&self, req: reqwest::Request) -> Result<reqwest::Response, reqwest::Error> {
+        let is_query = req.url().path().ends_with(QUERY_PATH_SUFFIX);
+        let response = self.inner.execute(req).await?;
+        if !is_query || !response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        let version = response.version();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?;
+        if asks_for_upgrade(&body) {
+            self.query_upgrades.inc();
+        }
+
+        let mut response = axum::http::Response::new(body);
+        *response.status_mut() = status;
+        *response.version_mut() = version;
+        *response.headers_mut() = headers;
+        Ok(reqwest::Response::from(response))
+    }
+}
+
+/// Whether the CBOR response of a query is a bHTTP response with an `ic-upgrade: true` header.
+fn asks_for_upgrade(body: &[u8]) -> bool {
+    let Ok(QueryResponse { reply: Some(reply) }) = serde_cbor::from_slice(body) else {
+        return false;
+    };
+    let Ok(message) = Message::read_bhttp(&mut Cursor::new(reply.arg.as_slice())) else {
+        return false;
+    };
+
+    message.header().get(UPGRADE_HEADER_NAME.as_bytes()) == Some(b"true".as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bhttp::{Mode, StatusCode as BhttpStatusCode};
+    use serde_cbor::Value;
+
+    use super::*;
+
+    fn query_response(upgrade: Option<&str>) -> Vec<u8> {
+        let mut message = Message::response(BhttpStatusCode::try_from(200_u16).unwrap());
+        if let Some(upgrade) = upgrade {
+            message.put_header(UPGRADE_HEADER_NAME, upgrade);
+        }
+        let mut arg = Vec::new();
+        message.write_bhttp(Mode::KnownLength, &mut arg).unwrap();
+
+        let reply = BTreeMap::from([(Value::Text("arg".to_string()), Value::Bytes(arg))]);
+        let response = BTreeMap::from([
+            (
+                Value::Text("status".to_string()),
+                Value::Text("replied".to_string()),
+            ),
+            (Value::Text("reply".to_string()), Value::Map(reply)),
+        ]);
+        serde_cbor::to_vec(&Value::Map(response)).unwrap()
+    }
+
+    #[test]
+    fn only_the_responses_with_the_upgrade_header_ask_for_an_upgrade() {
+        assert!(asks_for_upgrade(&query_response(Some("true"))));
+        assert!(!asks_for_upgrade(&query_response(Some("false"))));
+        assert!(!asks_for_upgrade(&query_response(None)));
+        assert!(!asks_for_upgrade(b"Not CBOR"));
+    }
+}
@@ -1,32 +1,180 @@
-use std::path::PathBuf;
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc, time::Duration};
 
+use anyhow::Context;
+use clap::ValueEnum;
 use ic_gateway::ic_bn_lib::reqwest::Url;
 use pocket_ic::{
-    PocketIcBuilder,
+    PocketIcBuilder, PocketIcState,
     common::rest::{IcpFeatures, IcpFeaturesConfig},
     nonblocking::PocketIc,
 };
+use serde::Deserialize;
+use tokio::time::Instant;
 
-pub async fn start_pocket_ic(pic_path: &str) -> (PocketIc, Url) {
-    let pic = PocketIcBuilder::new()
-        .with_server_binary(PathBuf::from(pic_path))
-        .with_application_subnet()
-        .with_icp_features(IcpFeatures {
-            registry: None,
-            cycles_minting: Some(IcpFeaturesConfig::DefaultConfig),
-            icp_token: None,
-            cycles_token: Some(IcpFeaturesConfig::DefaultConfig),
-            nns_governance: None,
-            sns: None,
-            ii: Some(IcpFeaturesConfig::DefaultConfig),
-            nns_ui: None,
-            bitcoin: None,
-            canister_migration: None,
-            dogecoin: None,
-        })
-        .build_async()
-        .await;
+use crate::state::StateDir;
+
+const PACKAGE_DIR: &str = env!("CARGO_MANIFEST_DIR");
+const POCKET_IC_SERVER_BIN_PATH: &str = "bin/pocket-ic";
+
+pub const DEFAULT_APPLICATION_SUBNETS: usize = 1;
+
+/// The system canisters that PocketIC can set up, see [IcpFeatures].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IcpFeature {
+    Registry,
+    CyclesMinting,
+    IcpToken,
+    CyclesToken,
+    NnsGovernance,
+    Sns,
+    Ii,
+    NnsUi,
+    Bitcoin,
+    CanisterMigration,
+    Dogecoin,
+}
+
+impl IcpFeature {
+    pub const DEFAULTS: [IcpFeature; 3] = [
+        IcpFeature::CyclesMinting,
+        IcpFeature::CyclesToken,
+        IcpFeature::Ii,
+    ];
+}
+
+pub struct PocketIcConfig {
+    pub server_binary: PathBuf,
+    pub nns_subnet: bool,
+    pub system_subnets: usize,
+    pub fiduciary_subnet: bool,
+    pub application_subnets: usize,
+    pub icp_features: BTreeSet<IcpFeature>,
+    /// If set, the state is loaded from this directory on startup and saved to it by [stop_pocket_ic].
+    pub state_dir: Option<StateDir>,
+}
+
+impl PocketIcConfig {
+    /// The PocketIC server binary downloaded to the `bin` directory of this package.
+    pub fn default_server_binary() -> PathBuf {
+        PathBuf::from(PACKAGE_DIR).join(POCKET_IC_SERVER_BIN_PATH)
+    }
+}
+
+impl Default for PocketIcConfig {
+    fn default() -> Self {
+        Self {
+            server_binary: Self::default_server_binary(),
+            nns_subnet: false,
+            system_subnets: 0,
+            fiduciary_subnet: false,
+            application_subnets: DEFAULT_APPLICATION_SUBNETS,
+            icp_features: BTreeSet::from(IcpFeature::DEFAULTS),
+            state_dir: None,
+        }
+    }
+}
+
+pub async fn start_pocket_ic(config: &PocketIcConfig) -> Result<(PocketIc, Url), anyhow::Error> {
+    let mut builder = PocketIcBuilder::new().with_server_binary(config.server_binary.clone());
+
+    let has_state = match &config.state_dir {
+        Some(state_dir) => {
+            let current = state_dir.current();
+            std::fs::create_dir_all(&current).with_context(|| {
+                format!("Failed to create state directory {}", current.display())
+            })?;
+
+            let has_state = state_dir.has_state();
+            builder = builder.with_state(PocketIcState::new_from_path(current));
+            has_state
+        }
+        None => false,
+    };
+
+    // The topology and the system canisters of a restored instance are part of its state
+    if !has_state {
+        builder = with_topology(builder, config);
+    }
+
+    let pic = builder.build_async().await;
     let url = pic.auto_progress().await;
 
-    (pic, url)
+    Ok((pic, url))
+}
+
+/// Takes PocketIC back from the requests to the admin endpoints that are still running, which
+/// are given the shutdown timeout of the gateway to complete.
+///
+/// Fails if PocketIC is still in use after the timeout, since it can't be stopped and its state
+/// can't be saved then.
+pub async fn take_pocket_ic(
+    mut pic: Arc<PocketIc>,
+    timeout: Duration,
+) -> Result<PocketIc, anyhow::Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        pic = match Arc::try_unwrap(pic) {
+            Ok(pic) => return Ok(pic),
+            Err(pic) => pic,
+        };
+        if Instant::now() >= deadline {
+            anyhow::bail!(
+                "PocketIC is still in use after {}s, so it was not stopped and its state was not saved",
+                timeout.as_secs()
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Stops the PocketIC instance, persisting its state if it was started with a state directory.
+pub async fn stop_pocket_ic(pic: PocketIc, config: &PocketIcConfig) {
+    if config.state_dir.is_some() {
+        // The state was created from a path, so it's kept on disk when dropped
+        drop(pic.drop_and_take_state().await);
+    } else {
+        pic.drop().await;
+    }
+}
+
+fn with_topology(mut builder: PocketIcBuilder, config: &PocketIcConfig) -> PocketIcBuilder {
+    builder = builder.with_icp_features(icp_features(&config.icp_features));
+
+    if config.nns_subnet {
+        builder = builder.with_nns_subnet();
+    }
+    for _ in 0..config.system_subnets {
+        builder = builder.with_system_subnet();
+    }
+    if config.fiduciary_subnet {
+        builder = builder.with_fiduciary_subnet();
+    }
+    for _ in 0..config.application_subnets {
+        builder = builder.with_application_subnet();
+    }
+
+    builder
+}
+
+fn icp_features(enabled: &BTreeSet<IcpFeature>) -> IcpFeatures {
+    let feature_config = |feature: IcpFeature| {
+        enabled
+            .contains(&feature)
+            .then_some(IcpFeaturesConfig::DefaultConfig)
+    };
+
+    IcpFeatures {
+        registry: feature_config(IcpFeature::Registry),
+        cycles_minting: feature_config(IcpFeature::CyclesMinting),
+        icp_token: feature_config(IcpFeature::IcpToken),
+        cycles_token: feature_config(IcpFeature::CyclesToken),
+        nns_governance: feature_config(IcpFeature::NnsGovernance),
+        sns: feature_config(IcpFeature::Sns),
+        ii: feature_config(IcpFeature::Ii),
+        nns_ui: feature_config(IcpFeature::NnsUi),
+        bitcoin: feature_config(IcpFeature::Bitcoin),
+        canister_migration: feature_config(IcpFeature::CanisterMigration),
+        dogecoin: feature_config(IcpFeature::Dogecoin),
+    }
 }
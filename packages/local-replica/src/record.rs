@@ -0,0 +1,507 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Context;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::signature::{SignatureComponents, parse_signature_headers};
+
+const HAR_EXTENSION: &str = "har";
+const HAR_VERSION: &str = "1.2";
+const BASE64_ENCODING: &str = "base64";
+
+/// Bodies larger than this are forwarded without being recorded.
+pub const MAX_RECORDED_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Closes the `entries` array and the `log` object of a HAR file, after the last entry.
+const HAR_SUFFIX: &[u8] = b"\n]}}\n";
+
+/// A request forwarded by the gateway and the response it got, as written to the recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedExchange {
+    /// RFC 3339 timestamp of when the request was received.
+    pub started_at: String,
+    pub duration_ms: f64,
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: RecordedBody,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<SignatureComponents>,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: RecordedBody,
+}
+
+/// A body as text, or base64 encoded if it isn't valid UTF-8, like the HAR `content` object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedBody {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// The body was larger than [MAX_RECORDED_BODY_SIZE] or failed while being read, so
+    /// `text` is empty.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub omitted: bool,
+}
+
+impl RecordedBody {
+    pub(crate) fn new(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Self {
+                text: text.to_string(),
+                encoding: None,
+                omitted: false,
+            },
+            Err(_) => Self {
+                text: BASE64.encode(bytes),
+                encoding: Some(BASE64_ENCODING.to_string()),
+                omitted: false,
+            },
+        }
+    }
+
+    fn omitted() -> Self {
+        Self {
+            text: String::new(),
+            encoding: None,
+            omitted: true,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, anyhow::Error> {
+        if self.omitted {
+            anyhow::bail!("The body was too large or failed to be recorded");
+        }
+
+        match self.encoding.as_deref() {
+            Some(BASE64_ENCODING) => Ok(BASE64.decode(&self.text)?),
+            Some(encoding) => anyhow::bail!("Unsupported body encoding {encoding}"),
+            None => Ok(self.text.as_bytes().to_vec()),
+        }
+    }
+}
+
+enum RecordFormat {
+    /// One [RecordedExchange] per line, appended as the requests complete.
+    Jsonl(File),
+    /// A HAR file, whose entries are appended before [HAR_SUFFIX], so that the file is
+    /// valid after every request.
+    Har { file: File, num_entries: usize },
+}
+
+/// Writes the traffic of the gateway to a JSONL file, or to a HAR file if the path ends with `.har`.
+pub struct Recorder {
+    format: Mutex<RecordFormat>,
+    /// The scheme of the gateway, since the request URIs only have a path.
+    scheme: String,
+}
+
+impl Recorder {
+    /// A JSONL recording is appended to, while a HAR recording replaces the file.
+    pub fn new(path: PathBuf, scheme: &str) -> Result<Self, anyhow::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        let format = if is_har(&path) {
+            let mut file = File::create(&path)
+                .with_context(|| format!("Failed to create recording file {}", path.display()))?;
+            let header = serde_json::json!({
+                "version": HAR_VERSION,
+                "creator": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+            });
+            // The header object is written without its closing brace, to add the entries
+            let header = serde_json::to_string(&header)?;
+            write!(
+                file,
+                "{{\"log\":{},\"entries\":[",
+                &header[..header.len() - 1]
+            )?;
+            file.write_all(HAR_SUFFIX)?;
+
+            RecordFormat::Har {
+                file,
+                num_entries: 0,
+            }
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open recording file {}", path.display()))?;
+            RecordFormat::Jsonl(file)
+        };
+
+        Ok(Self {
+            format: Mutex::new(format),
+            scheme: scheme.to_string(),
+        })
+    }
+
+    fn record(&self, exchange: RecordedExchange) -> Result<(), anyhow::Error> {
+        let mut format = self.format.lock().unwrap();
+
+        match &mut *format {
+            RecordFormat::Jsonl(file) => {
+                let line = serde_json::to_string(&exchange)?;
+                writeln!(file, "{line}")?;
+            }
+            RecordFormat::Har { file, num_entries } => {
+                let entry = serde_json::to_string(&har_entry(&exchange, &self.scheme))?;
+                let separator = if *num_entries == 0 { "\n" } else { ",\n" };
+
+                // The new entry overwrites the suffix, which is written again after it
+                file.seek(SeekFrom::End(-(HAR_SUFFIX.len() as i64)))?;
+                write!(file, "{separator}{entry}")?;
+                file.write_all(HAR_SUFFIX)?;
+                *num_entries += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the exchanges of a recording written by [Recorder].
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedExchange>, anyhow::Error> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read recording file {}", path.display()))?;
+
+    if is_har(path) {
+        let har: serde_json::Value = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse HAR file {}", path.display()))?;
+        let entries = har["log"]["entries"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+
+        return entries
+            .into_iter()
+            .map(|entry| {
+                serde_json::from_value(entry["_exchange"].clone())
+                    .context("The HAR entry wasn't written by the replica recorder")
+            })
+            .collect();
+    }
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Failed to parse line {} of {}", i + 1, path.display()))
+        })
+        .collect()
+}
+
+/// Middleware that buffers the request and the response to record them.
+pub async fn record_traffic(
+    State(recorder): State<Arc<Recorder>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started_at = chrono::Utc::now().to_rfc3339();
+    let start = Instant::now();
+
+    let (parts, body) = request.into_parts();
+    let (body, request_body) = buffer_body(body).await;
+    let recorded_request = RecordedRequest {
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        headers: header_pairs(&parts.headers),
+        body: recorded_body(request_body.as_deref()),
+        signatures: parse_signature_headers(&parts.headers),
+    };
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    let (parts, body) = response.into_parts();
+    let (body, response_body) = buffer_body(body).await;
+
+    let exchange = RecordedExchange {
+        started_at,
+        duration_ms: start.elapsed().as_secs_f64() * 1_000.0,
+        request: recorded_request,
+        response: RecordedResponse {
+            status: parts.status.as_u16(),
+            headers: header_pairs(&parts.headers),
+            body: recorded_body(response_body.as_deref()),
+        },
+    };
+    if let Err(e) = recorder.record(exchange) {
+        eprintln!("Failed to record request: {e:#}");
+    }
+
+    Response::from_parts(parts, body)
+}
+
+/// Buffers the body to record it, unless it's larger than [MAX_RECORDED_BODY_SIZE].
+///
+/// Returns the body to forward, which streams the rest of a larger body, and the buffered
+/// body if it fits. A body that fails is forwarded with the same chunks and error, and
+/// isn't recorded.
+async fn buffer_body(body: Body) -> (Body, Option<Bytes>) {
+    let mut stream = body.into_data_stream();
+    let mut chunks = vec![];
+    let mut size = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let buffered = chunks.into_iter().map(Ok).chain([Err(e)]);
+                return (
+                    Body::from_stream(futures_util::stream::iter(buffered)),
+                    None,
+                );
+            }
+        };
+        size += chunk.len();
+        chunks.push(chunk);
+
+        if size > MAX_RECORDED_BODY_SIZE {
+            let buffered = futures_util::stream::iter(chunks.into_iter().map(Ok::<_, axum::Error>));
+            return (Body::from_stream(buffered.chain(stream)), None);
+        }
+    }
+
+    let body = Bytes::from(chunks.concat());
+    (Body::from(body.clone()), Some(body))
+}
+
+fn recorded_body(body: Option<&[u8]>) -> RecordedBody {
+    body.map_or_else(RecordedBody::omitted, RecordedBody::new)
+}
+
+pub fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).to_string(),
+            )
+        })
+        .collect()
+}
+
+/// The request URI only has a path, so the host is taken from the `host` header.
+fn absolute_url(request: &RecordedRequest, scheme: &str) -> String {
+    let host = request.header("host").unwrap_or("localhost");
+
+    format!("{scheme}://{host}{}", request.uri)
+}
+
+fn is_har(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(HAR_EXTENSION))
+}
+
+/// Converts the exchange to a HAR entry. The exchange itself is kept in the `_exchange`
+/// custom field of the entry, so that the recording can be read back losslessly.
+fn har_entry(exchange: &RecordedExchange, scheme: &str) -> serde_json::Value {
+    let header_objects = |headers: &[(String, String)]| {
+        headers
+            .iter()
+            .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+            .collect::<Vec<_>>()
+    };
+    let content_type =
+        |headers: &[(String, String)]| find_header(headers, "content-type").unwrap_or_default();
+
+    let request = &exchange.request;
+    let response = &exchange.response;
+
+    serde_json::json!({
+        "startedDateTime": exchange.started_at,
+        "time": exchange.duration_ms,
+        "request": {
+            "method": request.method,
+            "url": absolute_url(request, scheme),
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": header_objects(&request.headers),
+            "queryString": [],
+            "postData": {
+                "mimeType": content_type(&request.headers),
+                "text": request.body.text,
+            },
+            "headersSize": -1,
+            "bodySize": -1,
+        },
+        "response": {
+            "status": response.status,
+            "statusText": StatusCode::from_u16(response.status)
+                .ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or_default(),
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": header_objects(&response.headers),
+            "content": {
+                "size": -1,
+                "mimeType": content_type(&response.headers),
+                "text": response.body.text,
+                "encoding": response.body.encoding,
+            },
+            "redirectURL": "",
+            "headersSize": -1,
+            "bodySize": -1,
+        },
+        "cache": {},
+        "timings": { "send": 0, "wait": exchange.duration_ms, "receive": 0 },
+        "_exchange": exchange,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path in the temporary directory, removed before the test writes to it.
+    fn recording_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("local-replica-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn exchange(i: usize) -> RecordedExchange {
+        RecordedExchange {
+            started_at: "2024-01-01T00:00:00+00:00".to_string(),
+            duration_ms: 1.5,
+            request: RecordedRequest {
+                method: "POST".to_string(),
+                uri: format!("/api/todos/{i}"),
+                headers: vec![("host".to_string(), "localhost:4943".to_string())],
+                body: RecordedBody::new(br#"{"title":"Test"}"#),
+                signatures: vec![],
+            },
+            response: RecordedResponse {
+                status: 201,
+                headers: vec![("content-type".to_string(), "application/json".to_string())],
+                body: RecordedBody::new(&[0xff, 0x00, i as u8]),
+            },
+        }
+    }
+
+    fn har_entries(path: &Path) -> Vec<serde_json::Value> {
+        let har: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(har["log"]["version"], HAR_VERSION);
+        har["log"]["entries"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn har_file_is_valid_after_every_entry() {
+        let path = recording_path("entries.har");
+        let recorder = Recorder::new(path.clone(), "http").unwrap();
+        assert!(har_entries(&path).is_empty());
+
+        recorder.record(exchange(0)).unwrap();
+        let entries = har_entries(&path);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0]["request"]["url"],
+            "http://localhost:4943/api/todos/0"
+        );
+
+        for i in 1..5 {
+            recorder.record(exchange(i)).unwrap();
+        }
+        let entries = har_entries(&path);
+        assert_eq!(entries.len(), 5);
+        assert_eq!(
+            entries[4]["request"]["url"],
+            "http://localhost:4943/api/todos/4"
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn recordings_are_read_back() {
+        for name in ["round-trip.jsonl", "round-trip.har"] {
+            let path = recording_path(name);
+            let recorder = Recorder::new(path.clone(), "http").unwrap();
+            for i in 0..3 {
+                recorder.record(exchange(i)).unwrap();
+            }
+
+            let exchanges = read_recording(&path).unwrap();
+            assert_eq!(exchanges.len(), 3, "{name}");
+            for (i, recorded) in exchanges.iter().enumerate() {
+                let expected = exchange(i);
+                assert_eq!(recorded.request.uri, expected.request.uri, "{name}");
+                assert_eq!(
+                    recorded.request.body.to_bytes().unwrap(),
+                    expected.request.body.to_bytes().unwrap(),
+                    "{name}"
+                );
+                assert_eq!(
+                    recorded.response.body.to_bytes().unwrap(),
+                    [0xff, 0x00, i as u8],
+                    "{name}"
+                );
+            }
+
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_body_is_forwarded_with_its_error() {
+        let chunks = vec![
+            Ok(Bytes::from_static(b"first")),
+            Err(std::io::Error::other("upstream failed")),
+        ];
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+
+        let (body, recorded) = buffer_body(body).await;
+        assert!(recorded.is_none());
+
+        let mut stream = body.into_data_stream();
+        assert_eq!(stream.next().await.unwrap().unwrap(), "first");
+        assert!(stream.next().await.unwrap().is_err());
+    }
+}
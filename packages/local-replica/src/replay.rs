@@ -0,0 +1,328 @@
+use std::path::Path;
+
+use anyhow::Context;
+use ic_gateway::ic_bn_lib::{
+    ic_agent::{
+        Identity,
+        identity::{BasicIdentity, Prime256v1Identity, Secp256k1Identity},
+    },
+    reqwest::{self, Method, Url},
+};
+
+use crate::{
+    record::{RecordedExchange, find_header},
+    signature::{
+        BhttpRequest, SIGNATURE_HEADER_NAME, SIGNATURE_INPUT_HEADER_NAME,
+        SIGNATURE_KEY_HEADER_NAME, sign_request,
+    },
+};
+
+/// Headers compared when no other headers are selected.
+pub const DEFAULT_COMPARED_HEADERS: [&str; 1] = ["content-type"];
+
+/// Headers that are recomputed by the HTTP client, so they are not resent as recorded.
+const SKIPPED_REQUEST_HEADERS: [&str; 3] = ["content-length", "transfer-encoding", "connection"];
+
+pub struct ReplayOptions {
+    pub gateway_url: Url,
+    /// The client used to send the requests, which has to trust the gateway certificate with TLS.
+    pub client: reqwest::Client,
+    /// Response headers compared, in addition to the status and the body.
+    pub compared_headers: Vec<String>,
+    /// If set, the signed requests are signed again with this identity, since the
+    /// recorded signatures are likely expired.
+    pub identity: Option<Box<dyn Identity>>,
+}
+
+/// A part of a response that differs from the recording.
+#[derive(Debug, Clone)]
+pub struct ReplayDifference {
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayResult {
+    pub method: String,
+    pub uri: String,
+    pub differences: Vec<ReplayDifference>,
+}
+
+/// Loads an Ed25519, secp256k1 or P-256 identity from a PEM file, e.g. one exported by `dfx identity export`.
+pub fn load_identity(path: &Path) -> Result<Box<dyn Identity>, anyhow::Error> {
+    if let Ok(identity) = BasicIdentity::from_pem_file(path) {
+        return Ok(Box::new(identity));
+    }
+    if let Ok(identity) = Secp256k1Identity::from_pem_file(path) {
+        return Ok(Box::new(identity));
+    }
+
+    Prime256v1Identity::from_pem_file(path)
+        .map(|identity| Box::new(identity) as Box<dyn Identity>)
+        .with_context(|| format!("Failed to load identity from {}", path.display()))
+}
+
+/// Sends the recorded requests to the gateway one by one, in order, and compares
+/// the responses with the recorded ones.
+pub async fn replay(
+    exchanges: &[RecordedExchange],
+    options: &ReplayOptions,
+) -> Result<Vec<ReplayResult>, anyhow::Error> {
+    let mut results = vec![];
+
+    for exchange in exchanges {
+        let differences = replay_exchange(exchange, options).await?;
+        results.push(ReplayResult {
+            method: exchange.request.method.clone(),
+            uri: exchange.request.uri.clone(),
+            differences,
+        });
+    }
+
+    Ok(results)
+}
+
+async fn replay_exchange(
+    exchange: &RecordedExchange,
+    options: &ReplayOptions,
+) -> Result<Vec<ReplayDifference>, anyhow::Error> {
+    let request = &exchange.request;
+    let body = request.body.to_bytes()?;
+
+    let mut headers = request
+        .headers
+        .iter()
+        .filter(|(name, _)| {
+            !SKIPPED_REQUEST_HEADERS
+                .iter()
+                .any(|skipped| name.eq_ignore_ascii_case(skipped))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if let Some(identity) = &options.identity
+        && !request.signatures.is_empty()
+    {
+        let authority = request
+            .header("host")
+            .context("The recorded request has no host header")?;
+        let signature_headers = sign_request(
+            identity.as_ref(),
+            &request.signatures,
+            &BhttpRequest {
+                method: &request.method,
+                scheme: options.gateway_url.scheme(),
+                authority,
+                path: &request.uri,
+                headers: &headers,
+                body: &body,
+            },
+        )?;
+
+        headers.retain(|(name, _)| {
+            ![
+                SIGNATURE_HEADER_NAME,
+                SIGNATURE_INPUT_HEADER_NAME,
+                SIGNATURE_KEY_HEADER_NAME,
+            ]
+            .iter()
+            .any(|signature_header| name.eq_ignore_ascii_case(signature_header))
+        });
+        headers.extend(signature_headers);
+    }
+
+    let url = options.gateway_url.join(&request.uri)?;
+    let mut builder = options
+        .client
+        .request(Method::from_bytes(request.method.as_bytes())?, url.clone())
+        .body(body);
+    for (name, value) in &headers {
+        builder = builder.header(name, value);
+    }
+
+    let response = builder
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach the replica at {url}, is it running?"))?;
+
+    let status = response.status().as_u16();
+    let response_headers = response
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let response_body = response.bytes().await?;
+
+    let expected = &exchange.response;
+    let mut differences = vec![];
+
+    if status != expected.status {
+        differences.push(ReplayDifference {
+            field: "status".to_string(),
+            expected: expected.status.to_string(),
+            actual: status.to_string(),
+        });
+    }
+
+    for name in &options.compared_headers {
+        let expected_value = find_header(&expected.headers, name).unwrap_or_default();
+        let actual_value = find_header(&response_headers, name).unwrap_or_default();
+        if expected_value != actual_value {
+            differences.push(ReplayDifference {
+                field: format!("header {name}"),
+                expected: expected_value.to_string(),
+                actual: actual_value.to_string(),
+            });
+        }
+    }
+
+    // A body too large to be recorded can't be compared
+    if !expected.body.omitted && expected.body.to_bytes()? != response_body {
+        differences.push(ReplayDifference {
+            field: "body".to_string(),
+            expected: expected.body.text.clone(),
+            actual: String::from_utf8_lossy(&response_body).to_string(),
+        });
+    }
+
+    Ok(differences)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        Router,
+        body::Bytes,
+        extract::{Request, State},
+        http::{StatusCode, header::CONTENT_TYPE, request::Parts},
+    };
+    use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+    use p256::SecretKey;
+
+    use super::*;
+    use crate::{
+        record::{RecordedBody, RecordedRequest, RecordedResponse},
+        signature::{CALL_SIGNATURE_NAME, SignatureComponents},
+        signature_debug::signature_report,
+    };
+
+    type ReceivedRequest = Arc<Mutex<Option<(Parts, Bytes)>>>;
+
+    /// Serves a response that differs from the recorded one, and keeps the request it got.
+    async fn start_stub() -> (Url, ReceivedRequest) {
+        let received = ReceivedRequest::default();
+        let router = Router::new()
+            .fallback(
+                |State(received): State<ReceivedRequest>, request: Request| async move {
+                    let (parts, body) = request.into_parts();
+                    let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+                    *received.lock().unwrap() = Some((parts, body));
+
+                    (
+                        StatusCode::NOT_FOUND,
+                        [(CONTENT_TYPE, "text/plain")],
+                        "Not found",
+                    )
+                },
+            )
+            .with_state(received.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        (url, received)
+    }
+
+    fn recorded_exchange() -> RecordedExchange {
+        RecordedExchange {
+            started_at: "2024-01-01T00:00:00+00:00".to_string(),
+            duration_ms: 1.0,
+            request: RecordedRequest {
+                method: "POST".to_string(),
+                uri: "/api/todos".to_string(),
+                headers: vec![
+                    ("host".to_string(), "localhost:4943".to_string()),
+                    ("content-type".to_string(), "application/json".to_string()),
+                    ("content-length".to_string(), "16".to_string()),
+                ],
+                body: RecordedBody::new(br#"{"title":"Test"}"#),
+                signatures: vec![SignatureComponents {
+                    name: CALL_SIGNATURE_NAME.to_string(),
+                    input: vec![
+                        ("request_type".to_string(), "call".to_string()),
+                        (
+                            "canister_id".to_string(),
+                            "bkyz2-fmaaa-aaaaa-qaaaq-cai".to_string(),
+                        ),
+                        (
+                            "method_name".to_string(),
+                            "http_request_update_v2".to_string(),
+                        ),
+                        ("include_headers".to_string(), "content-type".to_string()),
+                        ("nonce".to_string(), BASE64.encode([7; 16])),
+                    ],
+                    signature: Some(BASE64.encode([0; 64])),
+                    key: None,
+                }],
+            },
+            response: RecordedResponse {
+                status: 201,
+                headers: vec![("content-type".to_string(), "application/json".to_string())],
+                body: RecordedBody::new(br#"{"ok":true}"#),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_reports_the_differences_and_signs_again() {
+        let (gateway_url, received) = start_stub().await;
+        let identity =
+            Prime256v1Identity::from_private_key(SecretKey::random(&mut rand::rngs::OsRng));
+
+        let results = replay(
+            &[recorded_exchange()],
+            &ReplayOptions {
+                gateway_url,
+                client: reqwest::Client::new(),
+                compared_headers: vec!["content-type".to_string()],
+                identity: Some(Box::new(identity)),
+            },
+        )
+        .await
+        .unwrap();
+
+        let differences = results[0]
+            .differences
+            .iter()
+            .map(|difference| {
+                (
+                    difference.field.as_str(),
+                    difference.expected.as_str(),
+                    difference.actual.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            differences,
+            [
+                ("status", "201", "404"),
+                ("header content-type", "application/json", "text/plain"),
+                ("body", r#"{"ok":true}"#, "Not found"),
+            ]
+        );
+
+        // The request is sent with a new signature, which passes the checks of the gateway
+        let (parts, body) = received.lock().unwrap().take().unwrap();
+        let report = signature_report(&parts, &body, "http");
+        assert_eq!(report.failed_check, None, "{report}");
+    }
+}
@@ -0,0 +1,443 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use axum::http::HeaderMap;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bhttp::{Message, Mode};
+use candid::Principal;
+use ic_gateway::ic_bn_lib::ic_agent::{Identity, RequestId, to_request_id};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+pub const SIGNATURE_HEADER_NAME: &str = "signature";
+pub const SIGNATURE_INPUT_HEADER_NAME: &str = "signature-input";
+pub const SIGNATURE_KEY_HEADER_NAME: &str = "signature-key";
+
+pub const CALL_SIGNATURE_NAME: &str = "sig_call";
+pub const READ_STATE_SIGNATURE_NAME: &str = "sig_read_state";
+pub const QUERY_SIGNATURE_NAME: &str = "sig_query";
+
+const SIGNATURE_INPUT_COMPONENTS_SEPARATOR: char = ';';
+const SIGNATURE_INPUTS_SEPARATOR: &str = ";";
+const SIGNATURES_SEPARATOR: &str = ",";
+const LIST_SEPARATOR: char = ',';
+const PATH_LABELS_SEPARATOR: char = '/';
+
+const REQUEST_STATUS_PATH_LABEL: &str = "request_status";
+
+/// Same default as the JS client.
+const INGRESS_EXPIRY: Duration = Duration::from_secs(5 * 60);
+
+/// One signature of a request, e.g. `sig_call`, decoded from the `signature`,
+/// `signature-input` and `signature-key` headers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignatureComponents {
+    pub name: String,
+    /// The `key=value` components of the signature input, in the order they were sent.
+    pub input: Vec<(String, String)>,
+    /// The base64 encoded signature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// The decoded JSON of the signature key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<serde_json::Value>,
+}
+
+impl SignatureComponents {
+    pub fn input_component(&self, key: &str) -> Option<&str> {
+        self.input
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn required_input_component(&self, key: &str) -> Result<&str, anyhow::Error> {
+        self.input_component(key)
+            .with_context(|| format!("The {} signature input has no {key}", self.name))
+    }
+
+    pub fn nonce(&self) -> Result<Vec<u8>, anyhow::Error> {
+        BASE64
+            .decode(self.required_input_component("nonce")?)
+            .context("The nonce is not base64 encoded")
+    }
+
+    pub fn include_headers(&self) -> Result<Vec<String>, anyhow::Error> {
+        Ok(self
+            .required_input_component("include_headers")?
+            .split(LIST_SEPARATOR)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+/// Decodes the signatures of a request. Every signature listed in the `signature-input`
+/// header is returned, even if it's missing from the other headers.
+pub fn parse_signature_headers(headers: &HeaderMap) -> Vec<SignatureComponents> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+
+    let signatures = parse_dictionary(header(SIGNATURE_HEADER_NAME));
+    let keys = parse_dictionary(header(SIGNATURE_KEY_HEADER_NAME));
+
+    parse_dictionary(header(SIGNATURE_INPUT_HEADER_NAME))
+        .into_iter()
+        .map(|(name, input)| {
+            let find = |entries: &[(String, String)]| {
+                entries
+                    .iter()
+                    .find(|(entry_name, _)| *entry_name == name)
+                    .map(|(_, value)| value.clone())
+            };
+
+            SignatureComponents {
+                input: parse_input_components(&input),
+                signature: find(&signatures),
+                key: find(&keys).and_then(|key| decode_signature_key(&key)),
+                name,
+            }
+        })
+        .collect()
+}
+
+/// Parses a header made of `name=:<base64>:` or `name=(<components>)` entries,
+/// returning the names and the values without their delimiters.
+fn parse_dictionary(value: &str) -> Vec<(String, String)> {
+    let mut entries = vec![];
+    let mut rest = value;
+
+    while let Some((name, tail)) = rest.split_once('=') {
+        let name = name.trim_matches(|c: char| c == ',' || c == ';' || c.is_whitespace());
+        let closing = match tail.chars().next() {
+            Some('(') => ')',
+            Some(':') => ':',
+            _ => break,
+        };
+        let Some(end) = tail[1..].find(closing) else {
+            break;
+        };
+
+        entries.push((name.to_string(), tail[1..end + 1].to_string()));
+        rest = &tail[end + 2..];
+    }
+
+    entries
+}
+
+fn parse_input_components(input: &str) -> Vec<(String, String)> {
+    input
+        .split(SIGNATURE_INPUT_COMPONENTS_SEPARATOR)
+        .filter_map(|component| component.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+fn decode_signature_key(key: &str) -> Option<serde_json::Value> {
+    let json = BASE64.decode(key).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+/// The parts of an HTTP request that are encoded in the bHTTP `arg` of the signed request.
+pub struct BhttpRequest<'a> {
+    pub method: &'a str,
+    /// The scheme the request was sent with, e.g. `https` when the gateway serves TLS.
+    pub scheme: &'a str,
+    pub authority: &'a str,
+    /// The path and query of the request.
+    pub path: &'a str,
+    pub headers: &'a [(String, String)],
+    pub body: &'a [u8],
+}
+
+impl BhttpRequest<'_> {
+    /// Encodes the request in the known-length bHTTP format, like the JS client. Only the
+    /// `include_headers` are encoded, sorted by name as they are iterated by the browser.
+    pub fn encode(&self, include_headers: &[String]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value))
+            .filter(|(name, _)| include_headers.contains(name))
+            .collect::<Vec<_>>();
+        headers.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut message = Message::request(
+            self.method.as_bytes().to_vec(),
+            self.scheme.as_bytes().to_vec(),
+            self.authority.as_bytes().to_vec(),
+            self.path.as_bytes().to_vec(),
+        );
+        for (name, value) in headers {
+            message.put_header(name.as_bytes(), value.as_bytes());
+        }
+        message.write_content(self.body);
+
+        let mut encoded = vec![];
+        message
+            .write_bhttp(Mode::KnownLength, &mut encoded)
+            .map_err(|e| anyhow::anyhow!("Failed to encode the request in bHTTP: {e}"))?;
+
+        Ok(encoded)
+    }
+}
+
+/// The request map of a `call` or `query` signature, hashed into its request id.
+#[derive(Debug, Serialize)]
+pub struct CanisterRequestContent {
+    pub request_type: String,
+    pub canister_id: Principal,
+    pub method_name: String,
+    pub sender: Principal,
+    pub nonce: ByteBuf,
+    pub ingress_expiry: u64,
+    pub arg: ByteBuf,
+}
+
+/// The request map of a `read_state` signature, hashed into its request id.
+#[derive(Debug, Serialize)]
+pub struct ReadStateRequestContent {
+    pub request_type: String,
+    pub sender: Principal,
+    pub nonce: ByteBuf,
+    pub ingress_expiry: u64,
+    pub paths: Vec<Vec<ByteBuf>>,
+}
+
+pub fn request_id(content: &impl Serialize) -> Result<RequestId, anyhow::Error> {
+    to_request_id(content).map_err(|e| anyhow::anyhow!("Failed to compute the request id: {e}"))
+}
+
+/// The `read_state` paths of a call, which cover its status. Like the JS client, the path
+/// holds the domain separated request id, i.e. the bytes that are signed.
+pub fn request_status_paths(call_request_id: &RequestId) -> Vec<Vec<ByteBuf>> {
+    vec![vec![
+        ByteBuf::from(REQUEST_STATUS_PATH_LABEL.as_bytes()),
+        ByteBuf::from(call_request_id.signable()),
+    ]]
+}
+
+/// Signs a request again with the given identity and a new ingress expiry, keeping the
+/// canister, method, nonce and covered headers of the `original` signatures.
+///
+/// Returns the new `signature`, `signature-input` and `signature-key` headers.
+pub fn sign_request(
+    identity: &dyn Identity,
+    original: &[SignatureComponents],
+    request: &BhttpRequest,
+) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let components = original
+        .iter()
+        .find(|signature| signature.name != READ_STATE_SIGNATURE_NAME)
+        .context("The request has no call or query signature")?;
+    let name = components.name.as_str();
+    let component = |key: &str| components.required_input_component(key);
+
+    let sender = identity
+        .sender()
+        .map_err(|e| anyhow::anyhow!("Failed to get the identity principal: {e}"))?;
+    let public_key = identity
+        .public_key()
+        .context("The identity has no public key")?;
+    let nonce = components.nonce()?;
+    let include_headers = components.include_headers()?;
+    let ingress_expiry = (SystemTime::now() + INGRESS_EXPIRY)
+        .duration_since(UNIX_EPOCH)?
+        .as_nanos() as u64;
+
+    let content = CanisterRequestContent {
+        request_type: component("request_type")?.to_string(),
+        canister_id: Principal::from_text(component("canister_id")?)?,
+        method_name: component("method_name")?.to_string(),
+        sender,
+        nonce: ByteBuf::from(nonce.clone()),
+        ingress_expiry,
+        arg: ByteBuf::from(request.encode(&include_headers)?),
+    };
+    let content_request_id = request_id(&content)?;
+
+    let mut signatures = vec![(
+        name.to_string(),
+        sign(identity, &content_request_id)?,
+        vec![
+            ("request_type", content.request_type.clone()),
+            ("canister_id", content.canister_id.to_text()),
+            ("method_name", content.method_name.clone()),
+            ("sender", sender.to_text()),
+            ("ingress_expiry", ingress_expiry.to_string()),
+            ("include_headers", include_headers.join(",")),
+            ("nonce", BASE64.encode(&nonce)),
+        ],
+    )];
+
+    if name == CALL_SIGNATURE_NAME {
+        let read_state = ReadStateRequestContent {
+            request_type: "read_state".to_string(),
+            sender,
+            nonce: ByteBuf::from(nonce.clone()),
+            ingress_expiry,
+            paths: request_status_paths(&content_request_id),
+        };
+
+        signatures.push((
+            READ_STATE_SIGNATURE_NAME.to_string(),
+            sign(identity, &request_id(&read_state)?)?,
+            vec![
+                ("request_type", read_state.request_type.clone()),
+                ("sender", sender.to_text()),
+                ("ingress_expiry", ingress_expiry.to_string()),
+                ("paths", encode_paths(&read_state.paths)),
+                ("nonce", BASE64.encode(&nonce)),
+            ],
+        ));
+    }
+
+    let signature_key = BASE64.encode(serde_json::to_vec(&serde_json::json!({
+        "pubKey": BASE64.encode(&public_key),
+    }))?);
+
+    let signature_header = signatures
+        .iter()
+        .map(|(name, signature, _)| format!("{name}=:{}:", BASE64.encode(signature)))
+        .collect::<Vec<_>>()
+        .join(SIGNATURES_SEPARATOR);
+    let signature_input_header = signatures
+        .iter()
+        .map(|(name, _, components)| {
+            let components = components
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>()
+                .join(";");
+            format!("{name}=({components})")
+        })
+        .collect::<Vec<_>>()
+        .join(SIGNATURE_INPUTS_SEPARATOR);
+    let signature_key_header = signatures
+        .iter()
+        .map(|(name, _, _)| format!("{name}=:{signature_key}:"))
+        .collect::<Vec<_>>()
+        .join(SIGNATURES_SEPARATOR);
+
+    Ok(vec![
+        (SIGNATURE_HEADER_NAME.to_string(), signature_header),
+        (
+            SIGNATURE_INPUT_HEADER_NAME.to_string(),
+            signature_input_header,
+        ),
+        (SIGNATURE_KEY_HEADER_NAME.to_string(), signature_key_header),
+    ])
+}
+
+fn sign(identity: &dyn Identity, request_id: &RequestId) -> Result<Vec<u8>, anyhow::Error> {
+    identity
+        .sign_arbitrary(&request_id.signable())
+        .map_err(|e| anyhow::anyhow!("Failed to sign the request: {e}"))?
+        .signature
+        .context("The identity returned no signature")
+}
+
+pub fn encode_paths(paths: &[Vec<ByteBuf>]) -> String {
+    paths
+        .iter()
+        .map(|path| {
+            path.iter()
+                .map(|label| BASE64.encode(label))
+                .collect::<Vec<_>>()
+                .join(&PATH_LABELS_SEPARATOR.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(&LIST_SEPARATOR.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Request, request::Parts};
+    use ic_gateway::ic_bn_lib::ic_agent::identity::Prime256v1Identity;
+    use p256::SecretKey;
+
+    use super::*;
+    use crate::signature_debug::signature_report;
+
+    const BODY: &[u8] = br#"{"title":"Test"}"#;
+
+    /// A request signed for `scheme`, with an absolute URI like the ones of HTTP/2 requests,
+    /// or only a path like the ones of HTTP/1.1 requests.
+    fn signed_request_parts(scheme: &str, absolute_uri: bool) -> Parts {
+        let identity =
+            Prime256v1Identity::from_private_key(SecretKey::random(&mut rand::rngs::OsRng));
+        let original = SignatureComponents {
+            name: CALL_SIGNATURE_NAME.to_string(),
+            input: vec![
+                ("request_type".to_string(), "call".to_string()),
+                (
+                    "canister_id".to_string(),
+                    "bkyz2-fmaaa-aaaaa-qaaaq-cai".to_string(),
+                ),
+                (
+                    "method_name".to_string(),
+                    "http_request_update_v2".to_string(),
+                ),
+                ("include_headers".to_string(), "content-type".to_string()),
+                ("nonce".to_string(), BASE64.encode([7; 16])),
+            ],
+            signature: None,
+            key: None,
+        };
+        let authority = "bkyz2-fmaaa-aaaaa-qaaaq-cai.localhost:4943";
+        let headers = vec![("content-type".to_string(), "application/json".to_string())];
+
+        let signature_headers = sign_request(
+            &identity,
+            &[original],
+            &BhttpRequest {
+                method: "POST",
+                scheme,
+                authority,
+                path: "/api/todos",
+                headers: &headers,
+                body: BODY,
+            },
+        )
+        .unwrap();
+
+        let uri = if absolute_uri {
+            format!("{scheme}://{authority}/api/todos")
+        } else {
+            "/api/todos".to_string()
+        };
+        let mut request = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("host", authority);
+        for (name, value) in headers.iter().chain(&signature_headers) {
+            request = request.header(name, value);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn signed_request_passes_the_signature_report() {
+        // The scheme of an absolute URI takes precedence over the one of the gateway
+        let parts = signed_request_parts("https", true);
+
+        let report = signature_report(&parts, BODY, "http");
+        assert_eq!(report.failed_check, None, "{report}");
+        assert_eq!(report.request_ids.len(), 2, "{report}");
+    }
+
+    #[test]
+    fn signature_report_uses_the_gateway_scheme_for_paths() {
+        let parts = signed_request_parts("https", false);
+
+        let report = signature_report(&parts, BODY, "https");
+        assert_eq!(report.failed_check, None, "{report}");
+
+        let report = signature_report(&parts, BODY, "http");
+        assert!(report.failed_check.is_some(), "{report}");
+    }
+}
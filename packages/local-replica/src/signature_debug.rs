@@ -0,0 +1,491 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Request, State},
+    http::request::Parts,
+    middleware::Next,
+    response::Response,
+    routing::get,
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use candid::Principal;
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use p256::pkcs8::DecodePublicKey;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+
+use crate::{
+    gateway::read_request_body,
+    signature::{
+        BhttpRequest, CALL_SIGNATURE_NAME, CanisterRequestContent, READ_STATE_SIGNATURE_NAME,
+        ReadStateRequestContent, SIGNATURE_INPUT_HEADER_NAME, SignatureComponents, encode_paths,
+        parse_signature_headers, request_id, request_status_paths,
+    },
+};
+
+pub const LAST_REQUESTS_PATH: &str = "/_debug/last-requests";
+
+/// Number of reports served on [LAST_REQUESTS_PATH].
+const REPORTS_CAPACITY: usize = 50;
+
+/// The maximum ingress expiry accepted by the IC, plus some clock drift.
+const MAX_INGRESS_EXPIRY: Duration = Duration::from_secs(5 * 60 + 30);
+
+/// Keeps the reports of the last signed requests received by the gateway.
+pub struct SignatureDebugger {
+    reports: Mutex<VecDeque<SignatureReport>>,
+    /// The scheme of the gateway, for the request URIs that only have a path.
+    scheme: String,
+}
+
+impl SignatureDebugger {
+    pub fn new(scheme: &str) -> Self {
+        Self {
+            reports: Mutex::default(),
+            scheme: scheme.to_string(),
+        }
+    }
+
+    fn push(&self, report: SignatureReport) {
+        let mut reports = self.reports.lock().unwrap();
+        if reports.len() == REPORTS_CAPACITY {
+            reports.pop_front();
+        }
+        reports.push_back(report);
+    }
+
+    /// The reports of the last signed requests, most recent first.
+    pub fn last_reports(&self) -> Vec<SignatureReport> {
+        self.reports.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+/// What the gateway would check on a signed request, recomputed from its headers and body.
+#[derive(Debug, Clone, Serialize)]
+pub struct SignatureReport {
+    pub received_at: String,
+    pub method: String,
+    pub uri: String,
+    pub principal: Option<String>,
+    pub expiry: Option<String>,
+    pub covered_headers: Vec<String>,
+    /// Hex encoded request id of each signature.
+    pub request_ids: Vec<(String, String)>,
+    pub checks: Vec<SignatureCheck>,
+    /// The name of the first check that failed, if any.
+    pub failed_check: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SignatureCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl fmt::Display for SignatureReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Signed request {} {}", self.method, self.uri)?;
+        writeln!(
+            f,
+            "  principal:       {}",
+            self.principal.as_deref().unwrap_or("-")
+        )?;
+        writeln!(
+            f,
+            "  expiry:          {}",
+            self.expiry.as_deref().unwrap_or("-")
+        )?;
+        writeln!(f, "  covered headers: {}", self.covered_headers.join(", "))?;
+        for (name, request_id) in &self.request_ids {
+            writeln!(f, "  {name} request id: {request_id}")?;
+        }
+        for check in &self.checks {
+            let status = if check.passed { "ok" } else { "FAILED" };
+            writeln!(f, "  [{status}] {}: {}", check.name, check.detail)?;
+        }
+        match &self.failed_check {
+            Some(check) => write!(f, "  => the {check} check failed"),
+            None => write!(f, "  => all checks passed"),
+        }
+    }
+}
+
+pub fn signature_debug_router(debugger: Arc<SignatureDebugger>) -> Router {
+    Router::new()
+        .route(LAST_REQUESTS_PATH, get(last_requests_handler))
+        .with_state(debugger)
+}
+
+async fn last_requests_handler(
+    State(debugger): State<Arc<SignatureDebugger>>,
+) -> Json<Vec<SignatureReport>> {
+    Json(debugger.last_reports())
+}
+
+/// Middleware that reports on every request with a `signature-input` header, and
+/// forwards it unchanged.
+pub async fn debug_signatures(
+    State(debugger): State<Arc<SignatureDebugger>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !request.headers().contains_key(SIGNATURE_INPUT_HEADER_NAME) {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = match read_request_body(body).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    let report = signature_report(&parts, &body, &debugger.scheme);
+    if report.failed_check.is_some() {
+        tracing::warn!("{report}");
+    } else {
+        tracing::info!("{report}");
+    }
+    debugger.push(report);
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Recomputes the request ids of the signatures. The bHTTP request is encoded with the
+/// scheme of the request URI, or `default_scheme` if it only has a path.
+pub(crate) fn signature_report(
+    parts: &Parts,
+    body: &[u8],
+    default_scheme: &str,
+) -> SignatureReport {
+    let signatures = parse_signature_headers(&parts.headers);
+    let mut report = SignatureReport {
+        received_at: chrono::Utc::now().to_rfc3339(),
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        principal: None,
+        expiry: None,
+        covered_headers: vec![],
+        request_ids: vec![],
+        checks: vec![],
+        failed_check: None,
+    };
+
+    let Some(main) = signatures
+        .iter()
+        .find(|signature| signature.name != READ_STATE_SIGNATURE_NAME)
+    else {
+        push_check(
+            &mut report,
+            "headers",
+            Err("The signature-input header has no call or query signature".to_string()),
+        );
+        return finish(report);
+    };
+
+    let headers_check = signatures
+        .iter()
+        .map(|signature| match (&signature.signature, &signature.key) {
+            (None, _) => Err(format!("No {} in the signature header", signature.name)),
+            (_, None) => Err(format!(
+                "No {} in the signature-key header, or it isn't base64 encoded JSON",
+                signature.name
+            )),
+            _ => Ok(()),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|_| {
+            let names = signatures
+                .iter()
+                .map(|signature| signature.name.as_str())
+                .collect::<Vec<_>>();
+            format!("Found the {} signatures", names.join(", "))
+        });
+    if !push_check(&mut report, "headers", headers_check) {
+        return finish(report);
+    }
+
+    let scheme = parts.uri.scheme_str().unwrap_or(default_scheme);
+    let content = match call_content(main, parts, body, scheme) {
+        Ok(content) => content,
+        Err(e) => {
+            push_check(&mut report, "signature input", Err(format!("{e:#}")));
+            return finish(report);
+        }
+    };
+    push_check(
+        &mut report,
+        "signature input",
+        Ok(format!(
+            "{} of {} on canister {}, with a {} bytes bHTTP arg",
+            content.request_type,
+            content.method_name,
+            content.canister_id,
+            content.arg.len()
+        )),
+    );
+    report.principal = Some(content.sender.to_text());
+    report.expiry = Some(format_expiry(content.ingress_expiry));
+    report.covered_headers = main.include_headers().unwrap_or_default();
+
+    push_check(
+        &mut report,
+        "expiry",
+        check_expiry(content.ingress_expiry).map(|_| "Within the accepted range".to_string()),
+    );
+
+    let missing_headers = report
+        .covered_headers
+        .iter()
+        .filter(|name| !parts.headers.contains_key(name.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+    push_check(
+        &mut report,
+        "covered headers",
+        if missing_headers.is_empty() {
+            Ok("All covered headers are present".to_string())
+        } else {
+            Err(format!(
+                "Covered headers missing from the request: {}",
+                missing_headers.join(", ")
+            ))
+        },
+    );
+
+    push_check(&mut report, "sender", check_sender(main, content.sender));
+
+    let Ok(call_request_id) = request_id(&content) else {
+        push_check(
+            &mut report,
+            "request id",
+            Err("Failed to compute the request id".to_string()),
+        );
+        return finish(report);
+    };
+    report
+        .request_ids
+        .push((main.name.clone(), hex::encode(call_request_id.to_vec())));
+    push_check(
+        &mut report,
+        &format!("{} signature", main.name),
+        verify_signature(main, &call_request_id.signable()),
+    );
+
+    if main.name == CALL_SIGNATURE_NAME {
+        match signatures
+            .iter()
+            .find(|signature| signature.name == READ_STATE_SIGNATURE_NAME)
+        {
+            Some(read_state) => {
+                let paths = request_status_paths(&call_request_id);
+                let expected_paths = encode_paths(&paths);
+                push_check(
+                    &mut report,
+                    "read_state paths",
+                    match read_state.input_component("paths") {
+                        Some(received) if received == expected_paths => {
+                            Ok("The paths cover the call request status".to_string())
+                        }
+                        received => Err(format!(
+                            "Expected {expected_paths}, received {}",
+                            received.unwrap_or("nothing")
+                        )),
+                    },
+                );
+
+                let read_state_content = ReadStateRequestContent {
+                    request_type: "read_state".to_string(),
+                    sender: content.sender,
+                    nonce: content.nonce.clone(),
+                    ingress_expiry: content.ingress_expiry,
+                    paths,
+                };
+                if let Ok(read_state_request_id) = request_id(&read_state_content) {
+                    report.request_ids.push((
+                        read_state.name.clone(),
+                        hex::encode(read_state_request_id.to_vec()),
+                    ));
+                    push_check(
+                        &mut report,
+                        &format!("{} signature", read_state.name),
+                        verify_signature(read_state, &read_state_request_id.signable()),
+                    );
+                }
+            }
+            None => {
+                push_check(
+                    &mut report,
+                    "read_state paths",
+                    Err(format!(
+                        "No {READ_STATE_SIGNATURE_NAME} signature, the response can't be polled"
+                    )),
+                );
+            }
+        }
+    }
+
+    finish(report)
+}
+
+/// Adds the result of a check to the report, and returns whether it passed.
+fn push_check(report: &mut SignatureReport, name: &str, result: Result<String, String>) -> bool {
+    let passed = result.is_ok();
+    report.checks.push(SignatureCheck {
+        name: name.to_string(),
+        passed,
+        detail: result.unwrap_or_else(|e| e),
+    });
+    passed
+}
+
+fn finish(mut report: SignatureReport) -> SignatureReport {
+    report.failed_check = report
+        .checks
+        .iter()
+        .find(|check| !check.passed)
+        .map(|check| check.name.clone());
+    report
+}
+
+/// Rebuilds the request map of the call or query signature, like the gateway does.
+fn call_content(
+    signature: &SignatureComponents,
+    parts: &Parts,
+    body: &[u8],
+    scheme: &str,
+) -> Result<CanisterRequestContent, anyhow::Error> {
+    let component = |key: &str| signature.required_input_component(key);
+
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let authority = parts
+        .headers
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default();
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let arg = BhttpRequest {
+        method: parts.method.as_str(),
+        scheme,
+        authority,
+        path,
+        headers: &headers,
+        body,
+    }
+    .encode(&signature.include_headers()?)?;
+
+    Ok(CanisterRequestContent {
+        request_type: component("request_type")?.to_string(),
+        canister_id: Principal::from_text(component("canister_id")?)?,
+        method_name: component("method_name")?.to_string(),
+        sender: Principal::from_text(component("sender")?)?,
+        nonce: ByteBuf::from(signature.nonce()?),
+        ingress_expiry: component("ingress_expiry")?.parse()?,
+        arg: ByteBuf::from(arg),
+    })
+}
+
+fn check_expiry(ingress_expiry: u64) -> Result<(), String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+
+    if ingress_expiry < now {
+        return Err(format!(
+            "Expired {}s ago",
+            Duration::from_nanos(now - ingress_expiry).as_secs()
+        ));
+    }
+    let remaining = Duration::from_nanos(ingress_expiry - now);
+    if remaining > MAX_INGRESS_EXPIRY {
+        return Err(format!(
+            "Expires in {}s, more than the maximum of {}s",
+            remaining.as_secs(),
+            MAX_INGRESS_EXPIRY.as_secs()
+        ));
+    }
+
+    Ok(())
+}
+
+/// The sender must be derived from the root public key of the delegation chain,
+/// or from the signing public key if there is no delegation.
+fn check_sender(signature: &SignatureComponents, sender: Principal) -> Result<String, String> {
+    let key = signature.key.as_ref().ok_or("No signature key")?;
+    let (root_key_field, root_key) = match key["delegationChain"]["pubKey"].as_str() {
+        Some(root_key) => ("delegationChain.pubKey", root_key),
+        None => (
+            "pubKey",
+            key["pubKey"]
+                .as_str()
+                .ok_or("The signature key has no pubKey")?,
+        ),
+    };
+    let root_key = BASE64
+        .decode(root_key)
+        .map_err(|_| format!("The {root_key_field} is not base64 encoded"))?;
+
+    let expected = Principal::self_authenticating(&root_key);
+    if expected == sender {
+        Ok(format!("The sender matches the {root_key_field}"))
+    } else {
+        Err(format!(
+            "The sender {sender} doesn't match the {root_key_field}, which is {expected}"
+        ))
+    }
+}
+
+/// Verifies P-256 signatures, like the ones of the JS client. Other key types and the
+/// delegations themselves are not verified.
+fn verify_signature(signature: &SignatureComponents, signable: &[u8]) -> Result<String, String> {
+    let public_key = signature
+        .key
+        .as_ref()
+        .and_then(|key| key["pubKey"].as_str())
+        .and_then(|key| BASE64.decode(key).ok())
+        .ok_or("The signature key has no base64 encoded pubKey")?;
+    let signature_bytes = signature
+        .signature
+        .as_deref()
+        .and_then(|signature| BASE64.decode(signature).ok())
+        .ok_or("The signature is not base64 encoded")?;
+
+    let Ok(verifying_key) = VerifyingKey::from_public_key_der(&public_key) else {
+        return Ok("Not verified, the pubKey is not a P-256 key".to_string());
+    };
+    let signature =
+        Signature::from_slice(&signature_bytes).map_err(|e| format!("Invalid signature: {e}"))?;
+
+    verifying_key
+        .verify(signable, &signature)
+        .map(|_| "Valid for the recomputed request id".to_string())
+        .map_err(|_| {
+            "Invalid for the recomputed request id, the signed request differs from the received one"
+                .to_string()
+        })
+}
+
+fn format_expiry(ingress_expiry: u64) -> String {
+    chrono::DateTime::from_timestamp_nanos(ingress_expiry as i64).to_rfc3339()
+}
@@ -0,0 +1,182 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+
+const CURRENT_STATE_DIR: &str = "current";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const LOCK_FILE: &str = "replica.lock";
+
+/// The directory where the PocketIC state is persisted across restarts.
+///
+/// The state of the running instance lives in `<root>/current`, and named
+/// snapshots of it are kept in `<root>/snapshots/<name>`.
+pub struct StateDir {
+    root: PathBuf,
+}
+
+impl StateDir {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn current(&self) -> PathBuf {
+        self.root.join(CURRENT_STATE_DIR)
+    }
+
+    /// Whether a previous PocketIC instance persisted its state in this directory.
+    pub fn has_state(&self) -> bool {
+        is_non_empty_dir(&self.current())
+    }
+
+    /// Marks the directory as used by a running replica until the lock is dropped, so that
+    /// snapshots aren't taken from or restored to a state that PocketIC is still writing.
+    pub fn lock(&self) -> Result<StateDirLock, anyhow::Error> {
+        fs::create_dir_all(&self.root)
+            .with_context(|| format!("Failed to create state directory {}", self.root.display()))?;
+
+        let path = self.root.join(LOCK_FILE);
+        let mut file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                self.ensure_unlocked()?;
+                return Err(e.into());
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {}", path.display()));
+            }
+        };
+        write!(file, "{}", std::process::id())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        Ok(StateDirLock { path })
+    }
+
+    fn ensure_unlocked(&self) -> Result<(), anyhow::Error> {
+        let path = self.root.join(LOCK_FILE);
+        if let Ok(pid) = fs::read_to_string(&path) {
+            bail!(
+                "The state directory {} is used by a running replica (pid {}), stop it first. \
+                 If no replica is running, remove {}",
+                self.root.display(),
+                pid.trim(),
+                path.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Copies the state persisted on the last shutdown, which is refused while a replica runs
+    /// since its current state is only written on shutdown.
+    pub fn save_snapshot(&self, name: &str) -> Result<(), anyhow::Error> {
+        self.ensure_unlocked()?;
+        if !self.has_state() {
+            bail!("No state found in {}", self.current().display());
+        }
+
+        let snapshot_dir = self.snapshot_dir(name)?;
+        if snapshot_dir.exists() {
+            fs::remove_dir_all(&snapshot_dir)
+                .with_context(|| format!("Failed to remove snapshot {}", snapshot_dir.display()))?;
+        }
+
+        copy_dir_all(&self.current(), &snapshot_dir)
+    }
+
+    pub fn restore_snapshot(&self, name: &str) -> Result<(), anyhow::Error> {
+        self.ensure_unlocked()?;
+        let snapshot_dir = self.snapshot_dir(name)?;
+        if !is_non_empty_dir(&snapshot_dir) {
+            bail!("Snapshot {name} not found in {}", snapshot_dir.display());
+        }
+
+        let current = self.current();
+        if current.exists() {
+            fs::remove_dir_all(&current)
+                .with_context(|| format!("Failed to remove state {}", current.display()))?;
+        }
+
+        copy_dir_all(&snapshot_dir, &current)
+    }
+
+    pub fn list_snapshots(&self) -> Result<Vec<String>, anyhow::Error> {
+        let snapshots_dir = self.root.join(SNAPSHOTS_DIR);
+        if !snapshots_dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut names = fs::read_dir(&snapshots_dir)
+            .with_context(|| format!("Failed to read {}", snapshots_dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+
+        Ok(names)
+    }
+
+    fn snapshot_dir(&self, name: &str) -> Result<PathBuf, anyhow::Error> {
+        let is_valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            && name != "."
+            && name != "..";
+        if !is_valid {
+            bail!(
+                "Invalid snapshot name {name:?}, only letters, digits, '-', '_' and '.' are allowed"
+            );
+        }
+
+        Ok(self.root.join(SNAPSHOTS_DIR).join(name))
+    }
+}
+
+/// Removes the lock file of a [StateDir] when dropped.
+pub struct StateDirLock {
+    path: PathBuf,
+}
+
+impl Drop for StateDirLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+fn is_non_empty_dir(path: &Path) -> bool {
+    fs::read_dir(path)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false)
+}
+
+fn copy_dir_all(src: &Path, dst: &Path) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(dst).with_context(|| format!("Failed to create {}", dst.display()))?;
+
+    for entry in fs::read_dir(src).with_context(|| format!("Failed to read {}", src.display()))? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target).with_context(|| {
+                format!(
+                    "Failed to copy {} to {}",
+                    entry.path().display(),
+                    target.display()
+                )
+            })?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose, SanType,
+};
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+const CA_COMMON_NAME: &str = "local-replica CA";
+const LEAF_DOMAINS: [&str; 2] = ["localhost", "*.localhost"];
+const LEAF_IPS: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::LOCALHOST),
+    IpAddr::V6(Ipv6Addr::LOCALHOST),
+];
+
+/// Where the certificate served by the gateway comes from.
+#[derive(Debug, Clone)]
+pub enum TlsSource {
+    /// A local CA and a certificate for `localhost` and `*.localhost` signed by it, generated
+    /// in this directory on the first run and reused afterwards, so the CA only has to be
+    /// trusted once.
+    Generated { dir: PathBuf },
+    /// A PEM certificate chain and private key supplied by the user.
+    Files { cert: PathBuf, key: PathBuf },
+}
+
+impl TlsSource {
+    pub fn cert_path(&self) -> PathBuf {
+        match self {
+            TlsSource::Generated { dir } => dir.join(CERT_FILE),
+            TlsSource::Files { cert, .. } => cert.clone(),
+        }
+    }
+
+    pub fn key_path(&self) -> PathBuf {
+        match self {
+            TlsSource::Generated { dir } => dir.join(KEY_FILE),
+            TlsSource::Files { key, .. } => key.clone(),
+        }
+    }
+
+    /// The generated CA certificate, which clients have to trust.
+    pub fn ca_cert_path(&self) -> Option<PathBuf> {
+        match self {
+            TlsSource::Generated { dir } => Some(dir.join(CA_CERT_FILE)),
+            TlsSource::Files { .. } => None,
+        }
+    }
+
+    /// Generates the certificates if needed, and loads them.
+    pub async fn rustls_config(&self) -> Result<RustlsConfig, anyhow::Error> {
+        if let TlsSource::Generated { dir } = self
+            && !dir.join(CERT_FILE).exists()
+        {
+            generate_certificates(dir)?;
+        }
+
+        RustlsConfig::from_pem_file(self.cert_path(), self.key_path())
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to load the TLS certificate {} and key {}",
+                    self.cert_path().display(),
+                    self.key_path().display()
+                )
+            })
+    }
+}
+
+/// Writes a CA certificate, and a certificate for [LEAF_DOMAINS] and [LEAF_IPS] signed by it.
+///
+/// The CA key is not kept, so no other certificate can be signed by the CA.
+fn generate_certificates(dir: &Path) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let ca_key = KeyPair::generate()?;
+    let mut ca_params = CertificateParams::new(Vec::<String>::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, CA_COMMON_NAME);
+    ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let ca_cert = ca_params.self_signed(&ca_key)?;
+    let issuer = Issuer::new(ca_params, ca_key);
+
+    let leaf_key = KeyPair::generate()?;
+    let mut leaf_params = CertificateParams::new(LEAF_DOMAINS.map(str::to_string).to_vec())?;
+    leaf_params
+        .subject_alt_names
+        .extend(LEAF_IPS.map(SanType::IpAddress));
+    leaf_params
+        .distinguished_name
+        .push(DnType::CommonName, LEAF_DOMAINS[0]);
+    leaf_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    let leaf_cert = leaf_params.signed_by(&leaf_key, &issuer)?;
+
+    let write = |file: &str, contents: String| {
+        let path = dir.join(file);
+        fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))
+    };
+    write(CA_CERT_FILE, ca_cert.pem())?;
+    write(CERT_FILE, leaf_cert.pem() + &ca_cert.pem())?;
+    write(KEY_FILE, leaf_key.serialize_pem())?;
+
+    println!(
+        "Generated a local CA in {}, trust it to avoid certificate warnings",
+        dir.join(CA_CERT_FILE).display()
+    );
+
+    Ok(())
+}
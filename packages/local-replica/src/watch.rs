@@ -0,0 +1,103 @@
+use std::{path::PathBuf, str::FromStr, time::Duration, time::SystemTime};
+
+use candid::Principal;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::{admin::AdminState, deploy::CanisterDeployment};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A wasm file to watch and the canister to upgrade when it changes, parsed from `<wasm-path>=<canister-id>`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct WatchTarget {
+    pub wasm: PathBuf,
+    pub canister_id: Principal,
+}
+
+impl FromStr for WatchTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (wasm, canister_id) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Expected <wasm-path>=<canister-id>, got {s}"))?;
+        let canister_id = Principal::from_text(canister_id)
+            .map_err(|e| format!("Invalid canister id {canister_id}: {e}"))?;
+
+        Ok(Self {
+            wasm: PathBuf::from(wasm),
+            canister_id,
+        })
+    }
+}
+
+impl TryFrom<String> for WatchTarget {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Upgrades the canister every time the wasm file changes, until the shutdown token is cancelled.
+///
+/// The file is polled, and a change is only deployed once the modification time is the
+/// same for two polls in a row, so that a module that is still being written is skipped.
+pub fn watch_canister(
+    admin_state: AdminState,
+    target: WatchTarget,
+    shutdown_token: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        println!(
+            "Watching {} for canister {}",
+            target.wasm.display(),
+            target.canister_id
+        );
+
+        let deployment = CanisterDeployment {
+            name: None,
+            wasm: target.wasm.clone(),
+            init_arg: None,
+            canister_id: Some(target.canister_id),
+        };
+        let mut deployed_modified = modified_time(&target);
+        let mut last_modified = deployed_modified;
+
+        loop {
+            tokio::select! {
+                _ = shutdown_token.cancelled() => break,
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+
+            let modified = modified_time(&target);
+            let is_settled = modified == last_modified;
+            last_modified = modified;
+            if modified.is_none() || modified == deployed_modified || !is_settled {
+                continue;
+            }
+            deployed_modified = modified;
+
+            match admin_state.deploy(&deployment).await {
+                Ok(deployed) if deployed.upgraded => {
+                    println!("Upgraded canister {}", deployed.canister_id)
+                }
+                Ok(deployed) => println!("Installed canister {}", deployed.canister_id),
+                Err(e) => eprintln!(
+                    "Failed to upgrade canister {} from {}: {e:#}",
+                    target.canister_id,
+                    target.wasm.display()
+                ),
+            }
+        }
+    })
+}
+
+fn modified_time(target: &WatchTarget) -> Option<SystemTime> {
+    std::fs::metadata(&target.wasm)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
@@ -0,0 +1,174 @@
+//! Starts an [Environment] with the todo app backend, and sends it signed requests.
+//!
+//! Requires the PocketIC server binary in `bin/pocket-ic` and the frontend build of the
+//! todo app, which is embedded in the backend.
+
+use std::{path::PathBuf, process::Command};
+
+use candid::Principal;
+use ic_gateway::ic_bn_lib::{
+    ic_agent::{Identity, identity::Prime256v1Identity},
+    reqwest::{Method, Url},
+};
+use local_replica::{
+    Environment,
+    signature::{
+        BhttpRequest, CALL_SIGNATURE_NAME, QUERY_SIGNATURE_NAME, SignatureComponents, sign_request,
+    },
+};
+use p256::SecretKey;
+use serde_json::Value;
+
+const WORKSPACE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../..");
+const BACKEND_PACKAGE: &str = "todo_app_backend";
+const WASM_TARGET: &str = "wasm32-unknown-unknown";
+
+const QUERY_METHOD: &str = "http_request_v2";
+const UPDATE_METHOD: &str = "http_request_update_v2";
+
+/// Builds the backend in a separate target directory, since the one of the tests is
+/// locked by cargo while they run.
+fn build_backend() -> PathBuf {
+    let target_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("backend");
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+
+    let status = Command::new(cargo)
+        .current_dir(WORKSPACE_DIR)
+        .args(["build", "--release", "--package", BACKEND_PACKAGE])
+        .args(["--target", WASM_TARGET])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .expect("Failed to run cargo");
+    assert!(status.success(), "Failed to build {BACKEND_PACKAGE}");
+
+    target_dir
+        .join(WASM_TARGET)
+        .join("release")
+        .join(format!("{BACKEND_PACKAGE}.wasm"))
+}
+
+/// Signs the request like the JS client, with a `sig_query` or a `sig_call` signature.
+fn signature_headers(
+    identity: &dyn Identity,
+    signature_name: &str,
+    canister_id: Principal,
+    url: &Url,
+    method: &Method,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Vec<(String, String)> {
+    let (request_type, method_name) = if signature_name == QUERY_SIGNATURE_NAME {
+        ("query", QUERY_METHOD)
+    } else {
+        ("call", UPDATE_METHOD)
+    };
+    let include_headers = headers
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>()
+        .join(",");
+    let template = SignatureComponents {
+        name: signature_name.to_string(),
+        input: vec![
+            ("request_type".to_string(), request_type.to_string()),
+            ("canister_id".to_string(), canister_id.to_text()),
+            ("method_name".to_string(), method_name.to_string()),
+            ("include_headers".to_string(), include_headers),
+            ("nonce".to_string(), "AAECAwQFBgcICQoLDA0ODw==".to_string()),
+        ],
+        signature: None,
+        key: None,
+    };
+
+    let authority = format!(
+        "{}:{}",
+        url.host_str().unwrap(),
+        url.port_or_known_default().unwrap()
+    );
+    let path = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+
+    sign_request(
+        identity,
+        &[template],
+        &BhttpRequest {
+            method: method.as_str(),
+            scheme: url.scheme(),
+            authority: &authority,
+            path: &path,
+            headers,
+            body,
+        },
+    )
+    .expect("Failed to sign the request")
+}
+
+#[tokio::test]
+#[ignore = "requires bin/pocket-ic and the frontend build of the todo app, run with --ignored"]
+async fn serves_signed_queries_and_updates() {
+    let wasm = build_backend();
+    let env = Environment::builder()
+        .with_wasm(wasm)
+        .start()
+        .await
+        .expect("Failed to start the environment");
+
+    let identity = Prime256v1Identity::from_private_key(SecretKey::random(&mut rand::rngs::OsRng));
+    let canister_id = env.canisters()[0].canister_id;
+    let canister_url = |path: &str| {
+        let mut url = env.gateway_url().join(path).unwrap();
+        url.query_pairs_mut()
+            .append_pair("canisterId", &canister_id.to_text());
+        url
+    };
+
+    // The metrics are served by the query method, without an upgrade
+    let url = canister_url("/metrics");
+    let headers = vec![("accept".to_string(), "application/json".to_string())];
+    let mut request = env.client().get(url.clone());
+    for (name, value) in headers.iter().chain(&signature_headers(
+        &identity,
+        QUERY_SIGNATURE_NAME,
+        canister_id,
+        &url,
+        &Method::GET,
+        &headers,
+        &[],
+    )) {
+        request = request.header(name, value);
+    }
+    let response = request.send().await.expect("Failed to send the query");
+    assert_eq!(response.status(), 200);
+    let metrics: Value =
+        serde_json::from_slice(&response.bytes().await.unwrap()).expect("Invalid metrics");
+    assert!(metrics.is_object());
+
+    // Creating a todo item requires an authenticated caller
+    let url = canister_url("/api/todos");
+    let headers = vec![("content-type".to_string(), "application/json".to_string())];
+    let body = br#"{"title":"Write an integration test"}"#;
+    let mut request = env.client().post(url.clone()).body(body.to_vec());
+    for (name, value) in headers.iter().chain(&signature_headers(
+        &identity,
+        CALL_SIGNATURE_NAME,
+        canister_id,
+        &url,
+        &Method::POST,
+        &headers,
+        body,
+    )) {
+        request = request.header(name, value);
+    }
+    let response = request.send().await.expect("Failed to send the update");
+    assert_eq!(response.status(), 201);
+    let todo: Value =
+        serde_json::from_slice(&response.bytes().await.unwrap()).expect("Invalid todo item");
+    assert_eq!(todo["ok"]["data"]["title"], "Write an integration test");
+
+    env.shutdown()
+        .await
+        .expect("Failed to shut down the environment");
+}